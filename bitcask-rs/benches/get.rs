@@ -0,0 +1,62 @@
+//! Compares gets through `read_at` with gets from memory mapped files
+
+#![allow(clippy::needless_return)]
+
+use std::{fs, path::PathBuf};
+
+use bitcask::{Options, Store};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const KEYS: usize = 10_000;
+
+fn open(dir: &PathBuf, mmap: bool) -> Store {
+    let options = Options::default()
+        .create_if_missing(true)
+        .max_file_size(1 << 20)
+        .mmap(mmap);
+    return Store::open(dir, options).unwrap();
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for value_sz in [100, 4096] {
+        let dir = std::env::temp_dir().join(format!("bitcask-bench-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        {
+            let store = open(&dir, false);
+            let value = vec![b'x'; value_sz];
+            for i in 0..KEYS {
+                store
+                    .put_bytes(format!("key{i}").as_bytes(), &value)
+                    .unwrap();
+            }
+        }
+        let keys: Vec<_> = (0..KEYS).map(|i| format!("key{i}").into_bytes()).collect();
+
+        let store = open(&dir, false);
+        group.bench_function(BenchmarkId::new("read_at", value_sz), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 7919) % KEYS;
+                black_box(store.get_bytes(&keys[i]).unwrap());
+            });
+        });
+        drop(store);
+
+        let store = open(&dir, true);
+        group.bench_function(BenchmarkId::new("mmap", value_sz), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 7919) % KEYS;
+                black_box(store.get_value(&keys[i]).unwrap());
+            });
+        });
+        drop(store);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_get);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+//! Overwrites one key many times, then merges the garbage away
+//!
+//! cargo run --example demo [dir]
+
+#![allow(clippy::needless_return)]
+
+use bitcask::{Options, Store};
+
+const PUTS: usize = 100_000;
+
+fn main() -> bitcask::Result<()> {
+    let dir = std::env::args().nth(1).unwrap_or_else(|| "dbs".to_string());
+    let store = Store::open(dir, Options::default().create_if_missing(true))?;
+    if let Some(recovery) = store.recovery() {
+        println!(
+            "Recovered from crash: discarded {} bytes of {} after offset {}",
+            recovery.discarded_bytes, recovery.file_id, recovery.offset
+        );
+    }
+
+    store.put("aman".to_string(), "a person".to_string())?;
+    for _ in 0..PUTS {
+        store.put("mac".to_string(), "a laptop".to_string())?;
+    }
+
+    let value = store.get("mac".to_string())?;
+    println!("Mac Value before: {:?}", value);
+
+    let stats = store.stats()?;
+    println!(
+        "Live bytes: {}, dead bytes: {} in {} files",
+        stats.live_bytes(),
+        stats.dead_bytes(),
+        stats.files.len()
+    );
+
+    store.merge_and_compact()?;
+
+    let value = store.get("mac".to_string())?;
+    println!("Mac Value After: {:?}", value);
+
+    let value = store.get("aman".to_string())?;
+    println!("Aman Value After: {:?}", value);
+
+    store.delete("mac".to_string())?;
+    let value = store.get("mac".to_string())?;
+    println!("Mac Value after: {:?}", value);
+
+    return Ok(());
+}
@@ -0,0 +1,172 @@
+use crate::error::{Error, Result};
+use integer_encoding::VarInt;
+use log::warn;
+
+use crate::record::{Record, FLAG_BATCH_BEGIN, FLAG_BATCH_COMMIT};
+
+/// Puts and deletes applied together by `Store::write`. On disk they sit
+/// between a begin marker holding the number of records and a commit marker
+/// holding the crc of all of them, and a batch without its commit is ignored
+/// when the store is opened.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    records: Vec<Record>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn put(&mut self, key: String, value: String) {
+        self.put_bytes(key.as_bytes(), value.as_bytes());
+    }
+
+    pub fn put_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.records.push(Record::new(key.to_vec(), value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: String) {
+        self.delete_bytes(key.as_bytes());
+    }
+
+    pub fn delete_bytes(&mut self, key: &[u8]) {
+        self.records.push(Record::tombstone(key.to_vec()));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.records.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.records.is_empty();
+    }
+
+    pub(crate) fn into_records(self) -> Vec<Record> {
+        return self.records;
+    }
+}
+
+pub fn begin_marker(count: usize) -> Record {
+    let mut marker = Record::new(vec![], count.encode_var_vec());
+    marker.flags = FLAG_BATCH_BEGIN;
+    return marker;
+}
+
+pub fn commit_marker(crc: u32) -> Record {
+    let mut marker = Record::new(vec![], crc.to_le_bytes().to_vec());
+    marker.flags = FLAG_BATCH_COMMIT;
+    return marker;
+}
+
+/// A batch whose commit marker hasn't shown up yet. Records are (record,
+/// position, size).
+#[derive(Debug)]
+pub struct PendingBatch {
+    pub begin_posi: u32,
+    count: usize,
+    crc: crc32fast::Hasher,
+    pub records: Vec<(Record, u32, u32)>,
+}
+
+impl PendingBatch {
+    pub fn begin(marker: &Record, begin_posi: u32) -> Result<Self> {
+        let (count, _) = usize::decode_var(&marker.value)
+            .ok_or_else(|| Error::corrupt("Failed to decode batch size"))?;
+        return Ok(Self {
+            begin_posi,
+            count,
+            crc: crc32fast::Hasher::new(),
+            records: vec![],
+        });
+    }
+
+    pub fn push(&mut self, record: Record, posi: u32, record_sz: u32) {
+        self.crc.update(&record.serialize());
+        self.records.push((record, posi, record_sz));
+    }
+
+    /// Bytes of the records read since the begin marker
+    pub fn size(&self) -> u32 {
+        return self.records.iter().map(|(_, _, record_sz)| record_sz).sum();
+    }
+
+    /// Whether `marker` commits exactly the records read since the begin marker
+    pub fn is_committed_by(&self, marker: &Record) -> bool {
+        let crc = self.crc.clone().finalize().to_le_bytes();
+        return self.records.len() == self.count && marker.value == crc;
+    }
+}
+
+/// Reassembles the batches in the records of a file read in order, for
+/// opening the store, replicating and replaying changes alike
+#[derive(Debug, Default)]
+pub struct Batches {
+    pending: Option<PendingBatch>,
+}
+
+impl Batches {
+    /// Takes the record at `posi` of file `file_id`. Records outside of a
+    /// batch go to `apply` right away, those of a batch once its commit checks
+    /// out. Returns how many bytes became garbage: markers, and the records of
+    /// a batch that never applies because another one begins before its commit
+    /// or the commit doesn't check out.
+    pub fn push<F>(
+        &mut self,
+        file_id: &str,
+        record: Record,
+        posi: u32,
+        record_sz: u32,
+        mut apply: F,
+    ) -> Result<u32>
+    where
+        F: FnMut(Record, u32, u32) -> Result<()>,
+    {
+        if record.is_batch_begin() {
+            let mut dead = record_sz;
+            if let Some(batch) = self.pending.take() {
+                warn!(
+                    "Discarding uncommitted batch at {file_id}:{}",
+                    batch.begin_posi
+                );
+                dead += batch.size();
+            }
+            let batch = PendingBatch::begin(&record, posi).map_err(|e| e.at(file_id, posi))?;
+            self.pending = Some(batch);
+            return Ok(dead);
+        }
+        if record.is_batch_commit() {
+            let mut dead = record_sz;
+            match self.pending.take() {
+                Some(batch) if batch.is_committed_by(&record) => {
+                    for (record, posi, record_sz) in batch.records {
+                        apply(record, posi, record_sz)?;
+                    }
+                }
+                Some(batch) => {
+                    warn!(
+                        "Discarding batch at {file_id}:{} failing its commit",
+                        batch.begin_posi
+                    );
+                    dead += batch.size();
+                }
+                None => warn!("Ignoring commit without a batch at {file_id}:{posi}"),
+            }
+            return Ok(dead);
+        }
+        match self.pending.as_mut() {
+            Some(batch) => batch.push(record, posi, record_sz),
+            None => apply(record, posi, record_sz)?,
+        }
+        return Ok(0);
+    }
+
+    /// The batch still waiting for its commit
+    pub fn pending(&self) -> Option<&PendingBatch> {
+        return self.pending.as_ref();
+    }
+
+    pub fn take_pending(&mut self) -> Option<PendingBatch> {
+        return self.pending.take();
+    }
+}
@@ -0,0 +1,265 @@
+//! Serves a store over TCP with a subset of the redis protocol, so redis
+//! clients and redis-cli can talk to it.
+//!
+//! Usage: bitcask-server [dir] [addr] [--replicate-on addr] [--replica-of addr],
+//! defaulting to `dbs` and 127.0.0.1:6379. `--replicate-on` serves replicas on
+//! another port, `--replica-of` follows such a primary until `REPLICAOF NO ONE`.
+
+#![allow(clippy::needless_return)]
+
+use std::{
+    io::{self, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use bitcask::{Error, Options, Replica, Store, WriteBatch};
+use resp::Reply;
+
+mod resp;
+
+/// Prints what the store logs to stderr, debug messages aside
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        return metadata.level() <= log::Level::Info;
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// What connections share
+struct Server {
+    store: Store,
+    /// Set while following a primary
+    replica: Mutex<Option<Replica>>,
+}
+
+fn main() -> bitcask::Result<()> {
+    log::set_logger(&Logger).expect("no other logger");
+    log::set_max_level(log::LevelFilter::Info);
+    let mut positional = vec![];
+    let (mut replicate_on, mut replica_of) = (None, None);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replicate-on" => replicate_on = args.next(),
+            "--replica-of" => replica_of = args.next(),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let dir = positional.next().unwrap_or("dbs".to_string());
+    let addr = positional.next().unwrap_or("127.0.0.1:6379".to_string());
+
+    let options = Options::default().create_if_missing(true);
+    let (store, replica) = match &replica_of {
+        Some(primary) => {
+            let replica = Replica::start(&dir, primary, options)?;
+            (replica.store().clone(), Some(replica))
+        }
+        None => (Store::open(&dir, options)?, None),
+    };
+    // kept for as long as the server runs
+    let _replicas = match replicate_on {
+        Some(replicate_on) => {
+            let listener = TcpListener::bind(&replicate_on)?;
+            println!("Serving replicas on {replicate_on}");
+            Some(bitcask::serve_replicas(&store, listener)?)
+        }
+        None => None,
+    };
+    let server = Arc::new(Server {
+        store,
+        replica: Mutex::new(replica),
+    });
+
+    let listener = TcpListener::bind(&addr)?;
+    println!("Serving {dir} on {addr}");
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&server, stream) {
+                println!("Connection closed with error: {e}");
+            }
+        });
+    }
+    return Ok(());
+}
+
+fn handle_connection(server: &Server, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match resp::read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // like redis, there is no telling where the next command starts
+                Reply::Error(format!("ERR {e}")).write_to(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = match quit {
+            true => Reply::Simple("OK"),
+            false => execute(server, &args),
+        };
+        reply.write_to(&mut writer)?;
+
+        // pipelined commands get their replies in one go
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+fn execute(server: &Server, args: &[Vec<u8>]) -> Reply {
+    let store = &server.store;
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+
+    let arity_ok = match name.as_str() {
+        "PING" => args.len() <= 1,
+        "GET" | "KEYS" => args.len() == 1,
+        "SET" => args.len() >= 2,
+        "DEL" | "EXISTS" => !args.is_empty(),
+        "MERGE" => args.is_empty(),
+        "REPLICAOF" => args.len() == 2,
+        "COMMAND" => true,
+        _ => return Reply::Error(format!("ERR unknown command '{name}'")),
+    };
+    if !arity_ok {
+        return Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        ));
+    }
+
+    let res = match name.as_str() {
+        "PING" => match args.first() {
+            Some(msg) => Ok(Reply::Bulk(Some(msg.clone()))),
+            None => Ok(Reply::Simple("PONG")),
+        },
+        "GET" => store.get_bytes(&args[0]).map(Reply::Bulk),
+        "SET" => set(store, args),
+        "DEL" => del(store, args),
+        "EXISTS" => exists(store, args),
+        "KEYS" => Ok(Reply::Array(
+            store
+                .keys()
+                .filter(|key| resp::glob_match(&args[0], key))
+                .map(|key| Reply::Bulk(Some(key)))
+                .collect(),
+        )),
+        "MERGE" => store.merge_and_compact().map(|_| Reply::Simple("OK")),
+        "REPLICAOF" => replica_of(server, args),
+        // redis-cli asks for the command docs when it connects
+        "COMMAND" => Ok(Reply::Array(vec![])),
+        _ => unreachable!(),
+    };
+    return match res {
+        Ok(reply) => reply,
+        // what redis answers when writing to a replica
+        Err(Error::Replica) => {
+            Reply::Error("READONLY You can't write against a read only replica.".to_string())
+        }
+        Err(e) => Reply::Error(format!("ERR {e}")),
+    };
+}
+
+/// SET key value [EX seconds | PX milliseconds]
+fn set(store: &Store, args: &[Vec<u8>]) -> bitcask::Result<Reply> {
+    let (key, value) = (&args[0], &args[1]);
+    let ttl = match &args[2..] {
+        [] => None,
+        [unit, n] => {
+            let n = std::str::from_utf8(n)
+                .ok()
+                .and_then(|n| n.parse::<u64>().ok())
+                .filter(|n| *n > 0);
+            let Some(n) = n else {
+                return Ok(Reply::Error(
+                    "ERR invalid expire time in 'set' command".to_string(),
+                ));
+            };
+            match unit.to_ascii_uppercase().as_slice() {
+                b"EX" => Some(Duration::from_secs(n)),
+                b"PX" => Some(Duration::from_millis(n)),
+                _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+            }
+        }
+        _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+    };
+
+    match ttl {
+        Some(ttl) => store.put_bytes_with_ttl(key, value, ttl)?,
+        None => store.put_bytes(key, value)?,
+    }
+    return Ok(Reply::Simple("OK"));
+}
+
+/// Deletes the keys that exist in one batch and returns how many there were
+fn del(store: &Store, keys: &[Vec<u8>]) -> bitcask::Result<Reply> {
+    let mut batch = WriteBatch::new();
+    for key in keys {
+        if store.get_bytes(key)?.is_some() {
+            batch.delete_bytes(key);
+        }
+    }
+    let deleted = batch.len();
+    store.write(batch)?;
+    return Ok(Reply::Integer(deleted as i64));
+}
+
+/// Only `REPLICAOF NO ONE`, which promotes a replica. Following another
+/// primary takes a restart with `--replica-of`.
+fn replica_of(server: &Server, args: &[Vec<u8>]) -> bitcask::Result<Reply> {
+    let no_one = args[0].eq_ignore_ascii_case(b"NO") && args[1].eq_ignore_ascii_case(b"ONE");
+    if !no_one {
+        return Ok(Reply::Error(
+            "ERR only REPLICAOF NO ONE is supported".to_string(),
+        ));
+    }
+    if let Some(replica) = server.replica.lock().unwrap().take() {
+        replica.promote()?;
+    }
+    return Ok(Reply::Simple("OK"));
+}
+
+fn exists(store: &Store, keys: &[Vec<u8>]) -> bitcask::Result<Reply> {
+    let mut n = 0;
+    for key in keys {
+        if store.get_bytes(key)?.is_some() {
+            n += 1;
+        }
+    }
+    return Ok(Reply::Integer(n));
+}
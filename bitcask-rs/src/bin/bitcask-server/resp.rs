@@ -0,0 +1,176 @@
+use std::io::{self, BufRead, Read, Write};
+
+/// Longest bulk string a client may send, same as redis' proto-max-bulk-len
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Replies this server sends, a subset of RESP2
+#[derive(Debug)]
+pub enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(w, "+{s}\r\n")?,
+            // a newline would end the error early
+            Reply::Error(e) => write!(w, "-{}\r\n", e.replace(['\r', '\n'], " "))?,
+            Reply::Integer(n) => write!(w, ":{n}\r\n")?,
+            Reply::Bulk(None) => write!(w, "$-1\r\n")?,
+            Reply::Bulk(Some(b)) => {
+                write!(w, "${}\r\n", b.len())?;
+                w.write_all(b)?;
+                w.write_all(b"\r\n")?;
+            }
+            Reply::Array(items) => {
+                write!(w, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(w)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, format!("Protocol error: {msg}"));
+}
+
+/// Reads a line without its `\r\n`, `None` when the client hung up
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    if r.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    return Ok(Some(line));
+}
+
+fn parse_len(bytes: &[u8], max: usize) -> io::Result<usize> {
+    let len = std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    if len > max {
+        return Err(protocol_error("length too large"));
+    }
+    return Ok(len);
+}
+
+/// Reads the next command, either an array of bulk strings as clients send
+/// them or an inline command as typed into telnet. `None` once the client
+/// hung up.
+pub fn read_command<R: BufRead>(r: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(r)? else {
+        return Ok(None);
+    };
+
+    let Some(count) = line.strip_prefix(b"*") else {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    };
+
+    let count = parse_len(count, MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(r)?.ok_or_else(|| protocol_error("unexpected end"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| protocol_error("expected '$'"))?;
+        let len = parse_len(len, MAX_BULK_LEN)?;
+
+        let mut arg = vec![];
+        r.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() != len + 2 || !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bad bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    return Ok(Some(args));
+}
+
+/// Redis style glob matching, supports `*`, `?`, `[...]` and `\` escapes
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where the last `*` was and what it matched up to, to retry with it
+    // swallowing one more byte
+    let mut star: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        if p < pattern.len() {
+            let matched = match pattern[p] {
+                b'*' => {
+                    star = Some((p, i));
+                    p += 1;
+                    continue;
+                }
+                b'?' => Some(p + 1),
+                b'[' => match_class(pattern, p, s[i]),
+                b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == s[i]).then_some(p + 2),
+                c => (c == s[i]).then_some(p + 1),
+            };
+            if let Some(next) = matched {
+                p = next;
+                i += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((star_p, star_i)) => {
+                p = star_p + 1;
+                i = star_i + 1;
+                star = Some((star_p, star_i + 1));
+            }
+            None => return false,
+        }
+    }
+    return pattern[p..].iter().all(|&c| c == b'*');
+}
+
+/// Matches `c` against the class starting at `pattern[p] == '['` and returns
+/// the position after it
+fn match_class(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    let mut j = p + 1;
+    let negate = pattern.get(j) == Some(&b'^');
+    if negate {
+        j += 1;
+    }
+    let mut matched = false;
+    while j < pattern.len() && pattern[j] != b']' {
+        if pattern[j] == b'\\' && j + 1 < pattern.len() {
+            matched |= pattern[j + 1] == c;
+            j += 2;
+        } else if j + 2 < pattern.len() && pattern[j + 1] == b'-' && pattern[j + 2] != b']' {
+            let (lo, hi) = (
+                pattern[j].min(pattern[j + 2]),
+                pattern[j].max(pattern[j + 2]),
+            );
+            matched |= lo <= c && c <= hi;
+            j += 3;
+        } else {
+            matched |= pattern[j] == c;
+            j += 1;
+        }
+    }
+    // an unterminated class matches like a literal '['
+    if j >= pattern.len() {
+        return (c == b'[').then_some(p + 1);
+    }
+    return (matched != negate).then_some(j + 1);
+}
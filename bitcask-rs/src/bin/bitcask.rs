@@ -0,0 +1,218 @@
+//! Looks into and fixes up a store from the command line. Run it without
+//! arguments for the list of commands.
+
+#![allow(clippy::needless_return)]
+
+use std::{error::Error, fs, path::Path, process::ExitCode};
+
+use bitcask::{
+    inspect::{self, DataFile, Entry},
+    Options, Store,
+};
+
+const USAGE: &str = "Usage: bitcask <command> [args]
+
+Commands:
+    dump <file>                   List the records of a data file
+    verify <dir>                  Check the crc of every record and hint in a store
+    get <dir> <key>               Print the value of a key
+    put <dir> <key> <value>       Set a key
+    delete <dir> <key>            Delete a key
+    stats <dir>                   Live and dead bytes per data file
+    merge <dir>                   Merge every data file
+    snapshot <dir> <dest>         Copy the store into dest, linking its data files
+    restore <snapshot> <dir>      Create a store from a snapshot
+    repair <file>                 Rewrite a data file without its corrupt records,
+                                  the store must not be open meanwhile";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Prints the store's warnings to stderr, away from the output
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        return metadata.level() <= log::Level::Warn;
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> ExitCode {
+    log::set_logger(&Logger).expect("no other logger");
+    log::set_max_level(log::LevelFilter::Warn);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+    let res = match args.as_slice() {
+        ["dump", file] => dump(Path::new(file)),
+        ["verify", dir] => verify(Path::new(dir)),
+        ["get", dir, key] => get(dir, key),
+        ["put", dir, key, value] => {
+            open(dir).and_then(|s| Ok(s.put(key.to_string(), value.to_string())?))
+        }
+        ["delete", dir, key] => open(dir).and_then(|s| Ok(s.delete(key.to_string())?)),
+        ["stats", dir] => stats(dir),
+        ["merge", dir] => open(dir).and_then(|s| Ok(s.merge_and_compact()?)),
+        ["snapshot", dir, dest] => open(dir).and_then(|s| Ok(s.snapshot(dest)?)),
+        ["restore", snapshot, dir] => restore(snapshot, dir),
+        ["repair", file] => repair(Path::new(file)),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    return match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    };
+}
+
+fn open(dir: &str) -> Result<Store> {
+    return Ok(Store::open(dir, Options::default())?);
+}
+
+fn open_read_only(dir: &str) -> Result<Store> {
+    return Ok(Store::open(dir, Options::default().read_only(true))?);
+}
+
+fn restore(snapshot: &str, dir: &str) -> Result<()> {
+    Store::restore(snapshot, dir, Options::default())?;
+    return Ok(());
+}
+
+fn get(dir: &str, key: &str) -> Result<()> {
+    match open_read_only(dir)?.get_bytes(key.as_bytes())? {
+        Some(value) => println!("{}", value.escape_ascii()),
+        None => return Err("Key not found".into()),
+    }
+    return Ok(());
+}
+
+fn dump(file: &Path) -> Result<()> {
+    let data_file = DataFile::open(file)?;
+    println!("version {}", data_file.version());
+    println!(
+        "{:>10} {:>8} {:>4} {:>10} {:>12} {:>10} key",
+        "offset", "size", "crc", "ts", "kind", "value_sz"
+    );
+
+    for entry in data_file.entries() {
+        match entry {
+            Entry::Record(r) => {
+                let mut notes = String::new();
+                if let Some(codec) = r.codec() {
+                    notes.push_str(&format!(" ({codec})"));
+                }
+                if let Some(expires_at) = r.expires_at {
+                    notes.push_str(&format!(" (expires {expires_at})"));
+                }
+                println!(
+                    "{:>10} {:>8} {:>4} {:>10} {:>12} {:>10} {}{notes}",
+                    r.offset,
+                    r.size,
+                    if r.crc_ok { "ok" } else { "BAD" },
+                    r.ts,
+                    r.kind(),
+                    r.value_sz,
+                    r.key.escape_ascii(),
+                );
+            }
+            Entry::Corrupt { offset, size } => {
+                println!("{offset:>10} {size:>8} corrupt bytes");
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Data files of `dir` sorted by id
+fn data_files(dir: &Path) -> Result<Vec<String>> {
+    let mut ids = vec![];
+    for e in fs::read_dir(dir)? {
+        let path = e?.path();
+        if path.is_file() && path.extension().is_none() {
+            if let Some(name) = path.file_name() {
+                ids.push(name.to_string_lossy().to_string());
+            }
+        }
+    }
+    ids.sort_by_key(|id| id.parse::<u64>().unwrap_or(0));
+    return Ok(ids);
+}
+
+fn verify(dir: &Path) -> Result<()> {
+    let mut damaged = 0;
+    for id in data_files(dir)? {
+        let data_file = DataFile::open(dir.join(&id))?;
+        let (mut good, mut bad, mut corrupt_bytes) = (0, 0, 0);
+        for entry in data_file.entries() {
+            match entry {
+                Entry::Record(r) if r.crc_ok => good += 1,
+                Entry::Record(_) => bad += 1,
+                Entry::Corrupt { size, .. } => corrupt_bytes += size,
+            }
+        }
+        let hint = match data_file.hint_ok()? {
+            Some(true) => "hint ok",
+            Some(false) => "hint BAD",
+            None => "no hint",
+        };
+        println!(
+            "{id}: {good} good records, {bad} bad records, {corrupt_bytes} corrupt bytes, {hint}"
+        );
+        if bad > 0 || corrupt_bytes > 0 || data_file.hint_ok()? == Some(false) {
+            damaged += 1;
+        }
+    }
+
+    if damaged > 0 {
+        return Err(format!("{damaged} damaged files").into());
+    }
+    return Ok(());
+}
+
+fn stats(dir: &str) -> Result<()> {
+    let stats = open_read_only(dir)?.stats()?;
+    println!(
+        "{:>12} {:>12} {:>12} {:>12} {:>8}",
+        "file", "total", "live", "dead", "garbage"
+    );
+    for f in stats.files.iter() {
+        println!(
+            "{:>12} {:>12} {:>12} {:>12} {:>7.1}%",
+            f.file_id,
+            f.total_bytes,
+            f.live_bytes,
+            f.dead_bytes,
+            f.garbage_ratio() * 100.0
+        );
+    }
+    println!(
+        "{} keys, {} bytes of which {} live and {} dead",
+        stats.keys,
+        stats.total_bytes(),
+        stats.live_bytes(),
+        stats.dead_bytes()
+    );
+    return Ok(());
+}
+
+fn repair(file: &Path) -> Result<()> {
+    let report = inspect::repair(file)?;
+    println!(
+        "Kept {} records, dropped {} bad records and {} bytes in total",
+        report.kept_records, report.dropped_records, report.dropped_bytes
+    );
+    return Ok(());
+}
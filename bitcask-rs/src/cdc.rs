@@ -0,0 +1,203 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, Seek, SeekFrom},
+    sync::mpsc,
+    time::Duration,
+};
+
+use crate::{
+    batch::Batches,
+    compression,
+    error::{Error, Result},
+    record::{self, Record},
+};
+
+/// A place in the data files, the start of a record or the end of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub file_id: String,
+    pub offset: u32,
+}
+
+/// A put or delete as it was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub key: Vec<u8>,
+    /// `None` for a delete
+    pub value: Option<Vec<u8>>,
+    pub ts: u32,
+    pub expires_at: Option<u32>,
+    /// Where the record is
+    pub file_id: String,
+    pub offset: u32,
+    /// Where the next record starts, to resume from with `Store::subscribe_from`
+    /// once this change is handled
+    pub next: Position,
+}
+
+impl Change {
+    pub(crate) fn from_record(
+        record: &Record,
+        file_id: &str,
+        offset: u32,
+        size: u32,
+    ) -> Result<Self> {
+        let value = match record.is_tombstone() {
+            true => None,
+            false => Some(compression::decompress(record.flags, record.value.clone())?),
+        };
+        return Ok(Self {
+            key: record.key.clone(),
+            value,
+            ts: record.ts,
+            expires_at: record.expires_at,
+            file_id: file_id.to_string(),
+            offset,
+            next: Position {
+                file_id: file_id.to_string(),
+                offset: offset + size,
+            },
+        });
+    }
+}
+
+/// Part of a data file to read again, for a subscription or a replica, through
+/// a handle of its own which keeps it readable even when a merge deletes it
+/// meanwhile
+#[derive(Debug)]
+pub(crate) struct ReplayRange {
+    pub file_id: String,
+    pub version: u8,
+    pub file: File,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The range a subscription is replaying
+#[derive(Debug)]
+struct Replaying {
+    file_id: String,
+    version: u8,
+    reader: BufReader<File>,
+    end: u32,
+}
+
+impl Replaying {
+    /// Starts reading `range` at its first record
+    fn start(range: ReplayRange) -> Result<Self> {
+        let start = range.start.max(record::records_start(range.version));
+        let mut reader = BufReader::new(range.file);
+        reader.seek(SeekFrom::Start(start as u64))?;
+        return Ok(Self {
+            file_id: range.file_id,
+            version: range.version,
+            reader,
+            end: range.end,
+        });
+    }
+
+    /// The next record with its offset and size, `None` past the end
+    fn next_record(&mut self) -> Result<Option<(Record, u32, u32)>> {
+        let offset = self.reader.stream_position()? as u32;
+        if offset >= self.end {
+            return Ok(None);
+        }
+        let (record, _) = Record::from_reader(&mut self.reader, self.version)
+            .map_err(|e| e.at(&self.file_id, offset))?;
+        let size = self.reader.stream_position()? as u32 - offset;
+        return Ok(Some((record, offset, size)));
+    }
+}
+
+/// Returned by `Store::subscribe` and `Store::subscribe_from`. Yields the
+/// changes already in the data files first when replaying, then every put and
+/// delete as it's written, in the order they were written. Changes queue up
+/// until they are taken, so a subscription nobody reads from grows without
+/// bounds. Dropping it unsubscribes.
+#[derive(Debug)]
+pub struct Subscription {
+    replay: VecDeque<ReplayRange>,
+    replaying: Option<Replaying>,
+    /// Changes of a committed batch that were read from the file together
+    ready: VecDeque<Change>,
+    batches: Batches,
+    live: mpsc::Receiver<Change>,
+}
+
+impl Subscription {
+    pub(crate) fn new(replay: Vec<ReplayRange>, live: mpsc::Receiver<Change>) -> Self {
+        return Self {
+            replay: replay.into(),
+            replaying: None,
+            ready: VecDeque::new(),
+            batches: Batches::default(),
+            live,
+        };
+    }
+
+    /// Waits at most `timeout` for the next change, `None` when there was none.
+    /// Fails with `Error::Closed` once the store was closed.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Change>> {
+        if let Some(change) = self.next_replayed() {
+            return change.map(Some);
+        }
+        return match self.live.recv_timeout(timeout) {
+            Ok(change) => Ok(Some(change)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::Closed),
+        };
+    }
+
+    /// The next change of the replay, which ends at the first error
+    fn next_replayed(&mut self) -> Option<Result<Change>> {
+        let res = self.replay_until_ready();
+        if res.is_err() {
+            self.replay.clear();
+            self.replaying = None;
+        }
+        return res.transpose();
+    }
+
+    fn replay_until_ready(&mut self) -> Result<Option<Change>> {
+        loop {
+            if let Some(change) = self.ready.pop_front() {
+                return Ok(Some(change));
+            }
+            if self.replaying.is_none() {
+                let Some(range) = self.replay.pop_front() else {
+                    return Ok(None);
+                };
+                self.replaying = Some(Replaying::start(range)?);
+            }
+            let f = self.replaying.as_mut().unwrap();
+            let Some((record, offset, size)) = f.next_record()? else {
+                // a batch can't span files, one without its commit never happened
+                self.replaying = None;
+                self.batches = Batches::default();
+                continue;
+            };
+
+            let ready = &mut self.ready;
+            self.batches
+                .push(&f.file_id, record, offset, size, |record, offset, size| {
+                    let change = Change::from_record(&record, &f.file_id, offset, size)
+                        .map_err(|e| e.at(&f.file_id, offset))?;
+                    ready.push_back(change);
+                    return Ok(());
+                })?;
+        }
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Change>;
+
+    /// Blocks until there is a change, `None` once the store is closed
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(change) = self.next_replayed() {
+            return Some(change);
+        }
+        return self.live.recv().ok().map(Ok);
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Why the compaction thread woke up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// A merge was asked for
+    Requested,
+    /// The check interval passed, the compaction policy decides what to merge
+    Timer,
+}
+
+/// Background thread that runs merges when asked to, so neither the caller
+/// nor the writers wait for one to finish.
+#[derive(Debug)]
+pub struct Compactor {
+    trigger: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// `merge` runs once per trigger, and every `interval` when one is given.
+    /// It returns false when the store is gone, which stops the thread.
+    pub fn spawn<F>(interval: Option<Duration>, merge: F) -> Self
+    where
+        F: Fn(Trigger) -> bool + Send + 'static,
+    {
+        let (trigger, triggered) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || loop {
+            let received = match interval {
+                Some(interval) => triggered.recv_timeout(interval),
+                None => triggered
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            let trigger = match received {
+                Ok(()) => Trigger::Requested,
+                Err(mpsc::RecvTimeoutError::Timeout) => Trigger::Timer,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+
+            if trigger == Trigger::Requested {
+                // triggers that piled up while merging are covered by the next merge
+                while triggered.try_recv().is_ok() {}
+            }
+            if !merge(trigger) {
+                return;
+            }
+        });
+
+        return Self {
+            trigger: Some(trigger),
+            thread: Some(thread),
+        };
+    }
+
+    pub fn trigger(&self) {
+        if let Some(trigger) = &self.trigger {
+            let _ = trigger.send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // closing the channel is what stops the thread
+        drop(self.trigger.take());
+        if let Some(t) = self.thread.take() {
+            // the last handle to the store can be the one a running merge holds
+            if t.thread().id() != thread::current().id() {
+                t.join().unwrap();
+            }
+        }
+    }
+}
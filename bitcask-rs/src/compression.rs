@@ -0,0 +1,35 @@
+use crate::{
+    error::{Error, Result},
+    options::Codec,
+    record::{FLAG_LZ4, FLAG_ZSTD},
+};
+
+impl Codec {
+    /// The record flag marking values compressed with this codec
+    pub fn flag(&self) -> u8 {
+        return match self {
+            Codec::Lz4 => FLAG_LZ4,
+            Codec::Zstd(_) => FLAG_ZSTD,
+        };
+    }
+
+    pub fn compress(&self, value: &[u8]) -> Result<Vec<u8>> {
+        return match self {
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(value)),
+            Codec::Zstd(level) => Ok(zstd::bulk::compress(value, *level)?),
+        };
+    }
+}
+
+/// Undoes the compression `flags` say the value went through, if any
+pub fn decompress(flags: u8, value: Vec<u8>) -> Result<Vec<u8>> {
+    if flags & FLAG_LZ4 != 0 {
+        return lz4_flex::decompress_size_prepended(&value)
+            .map_err(|e| Error::corrupt(format!("Failed to decompress lz4 value: {e}")));
+    }
+    if flags & FLAG_ZSTD != 0 {
+        return zstd::decode_all(&value[..])
+            .map_err(|e| Error::corrupt(format!("Failed to decompress zstd value: {e}")));
+    }
+    return Ok(value);
+}
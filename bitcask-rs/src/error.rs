@@ -0,0 +1,121 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::cdc::Position;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything the store can fail with
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// Bytes at `offset` of data file `file_id` that don't decode, or fail
+    /// their checksum
+    Corruption {
+        file_id: String,
+        offset: u32,
+        reason: String,
+    },
+    /// A key longer than `MAX_KEY_SIZE`
+    KeyTooLarge { size: usize },
+    /// A value longer than `MAX_VALUE_SIZE`, or a batch too big for a data file
+    ValueTooLarge { size: usize },
+    /// Another process has the store open for writing
+    Locked { dir: PathBuf },
+    /// The store was closed while waiting on it
+    Closed,
+    /// A write or merge on a store opened read only
+    ReadOnly,
+    /// A write or merge on a replica that wasn't promoted
+    Replica,
+    /// A position to resume from that was merged away, or that is past the end
+    /// of its file
+    PositionGone(Position),
+    /// Input the store can't work with, like a directory that isn't a snapshot
+    Invalid(String),
+}
+
+impl Error {
+    /// Corrupt data found where its location isn't known, the caller adds it
+    /// with `at`
+    pub(crate) fn corrupt(reason: impl Into<String>) -> Self {
+        return Error::Corruption {
+            file_id: String::new(),
+            offset: 0,
+            reason: reason.into(),
+        };
+    }
+
+    /// Puts a corruption without a location at `offset` of `file_id`
+    pub(crate) fn at(self, file_id: &str, offset: u32) -> Self {
+        return match self {
+            Error::Corruption {
+                file_id: unknown,
+                reason,
+                ..
+            } if unknown.is_empty() => Error::Corruption {
+                file_id: file_id.to_string(),
+                offset,
+                reason,
+            },
+            e => e,
+        };
+    }
+
+    pub(crate) fn invalid(reason: impl Into<String>) -> Self {
+        return Error::Invalid(reason.into());
+    }
+
+    /// Whether this is an `Io` error of `kind`
+    pub(crate) fn is_io(&self, kind: io::ErrorKind) -> bool {
+        return matches!(self, Error::Io(e) if e.kind() == kind);
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Corruption {
+                file_id, reason, ..
+            } if file_id.is_empty() => write!(f, "Corrupt data: {reason}"),
+            Error::Corruption {
+                file_id,
+                offset,
+                reason,
+            } => write!(f, "Corrupt data at {file_id}:{offset}: {reason}"),
+            Error::KeyTooLarge { size } => write!(f, "Key of {size} bytes is too large"),
+            Error::ValueTooLarge { size } => write!(f, "Value of {size} bytes is too large"),
+            Error::Locked { dir } => write!(
+                f,
+                "Store {} is locked, another process has it open for writing",
+                dir.display()
+            ),
+            Error::Closed => write!(f, "Store is closed"),
+            Error::ReadOnly => write!(f, "Store is opened read only"),
+            Error::Replica => write!(f, "Store is a replica, writes go to its primary"),
+            Error::PositionGone(position) => write!(
+                f,
+                "Position {}:{} was merged away or is past the end of its file",
+                position.file_id, position.offset
+            ),
+            Error::Invalid(reason) => write!(f, "{reason}"),
+        };
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        };
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        return Error::Io(e);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use integer_encoding::VarInt;
+use log::warn;
+
+use crate::{
+    error::Result,
+    record::{FLAG_EXPIRES, FLAG_TOMBSTONE},
+};
+
+/// Hints start with the magic followed by a version byte. Hints from before
+/// the header existed don't match it and are ignored like damaged ones.
+const MAGIC: &[u8; 4] = b"BCHT";
+const VERSION: u8 = 1;
+
+/// One key dir entry as persisted in a hint file. The data file it points into
+/// is implied by the hint's name, `<file_id>.hint`.
+#[derive(Debug)]
+pub struct HintEntry {
+    /// Record flags, tombstones are listed too so they keep shadowing older files
+    pub flags: u8,
+    pub ts: u32,
+    pub expires_at: Option<u32>,
+    pub key: Vec<u8>,
+    /// Size of the value as stored
+    pub value_sz: u32,
+    pub record_posi: u32,
+    pub record_sz: u32,
+}
+
+impl HintEntry {
+    pub fn is_tombstone(&self) -> bool {
+        return self.flags & FLAG_TOMBSTONE != 0;
+    }
+
+    pub fn is_expired(&self, now: u32) -> bool {
+        return self.expires_at.is_some_and(|expires_at| expires_at <= now);
+    }
+}
+
+/// On disk a hint is the header, then a sequence of flags, ts, [expires_at],
+/// key_sz, value_sz, record_posi, record_sz, key followed by a crc32 (little
+/// endian) of everything before it. `expires_at` is there when the flags have
+/// `FLAG_EXPIRES`, like in the data file.
+pub fn write_hint(path: &Path, entries: &[HintEntry]) -> Result<()> {
+    let mut buf = MAGIC.to_vec();
+    buf.push(VERSION);
+    for entry in entries {
+        buf.push(entry.flags);
+        buf.append(&mut entry.ts.encode_var_vec());
+        if let Some(expires_at) = entry.expires_at {
+            buf.append(&mut expires_at.encode_var_vec());
+        }
+        buf.append(&mut entry.key.len().encode_var_vec());
+        buf.append(&mut entry.value_sz.encode_var_vec());
+        buf.append(&mut entry.record_posi.encode_var_vec());
+        buf.append(&mut entry.record_sz.encode_var_vec());
+        buf.extend_from_slice(&entry.key);
+    }
+
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    fs::write(path, buf)?;
+    return Ok(());
+}
+
+/// Returns `None` when the hint is missing or damaged, in which case the
+/// caller should fall back to scanning the data file.
+pub fn read_hint(path: &Path) -> Result<Option<Vec<HintEntry>>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if bytes.len() < 4 {
+        return Ok(None);
+    }
+    let (mut buf, crc) = bytes.split_at(bytes.len() - 4);
+    let crc = u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]);
+    if crc32fast::hash(buf) != crc {
+        warn!("Checksum mismatch in hint {}. Ignoring it", path.display());
+        return Ok(None);
+    }
+    if buf.len() <= MAGIC.len() || &buf[..MAGIC.len()] != MAGIC || buf[MAGIC.len()] != VERSION {
+        warn!("Unknown format of hint {}. Ignoring it", path.display());
+        return Ok(None);
+    }
+    buf = &buf[MAGIC.len() + 1..];
+
+    let mut entries = vec![];
+    while !buf.is_empty() {
+        let Some(entry) = decode_entry(&mut buf) else {
+            warn!("Malformed entry in hint {}. Ignoring it", path.display());
+            return Ok(None);
+        };
+        entries.push(entry);
+    }
+    return Ok(Some(entries));
+}
+
+pub fn remove_hint(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => return Ok(()),
+    }
+}
+
+fn decode_entry(buf: &mut &[u8]) -> Option<HintEntry> {
+    let (flags, rest) = buf.split_first()?;
+    let flags = *flags;
+    *buf = rest;
+    let ts = decode_u32(buf)?;
+    let mut expires_at = None;
+    if flags & FLAG_EXPIRES != 0 {
+        expires_at = Some(decode_u32(buf)?);
+    }
+    let key_sz = decode_u32(buf)? as usize;
+    let value_sz = decode_u32(buf)?;
+    let record_posi = decode_u32(buf)?;
+    let record_sz = decode_u32(buf)?;
+
+    if buf.len() < key_sz {
+        return None;
+    }
+    let (key, rest) = buf.split_at(key_sz);
+    let key = key.to_vec();
+    *buf = rest;
+
+    return Some(HintEntry {
+        flags,
+        ts,
+        expires_at,
+        key,
+        value_sz,
+        record_posi,
+        record_sz,
+    });
+}
+
+fn decode_u32(buf: &mut &[u8]) -> Option<u32> {
+    let (n, read_bytes) = u32::decode_var(buf)?;
+    *buf = &buf[read_bytes..];
+    return Some(n);
+}
@@ -0,0 +1,214 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Error, Result},
+    hint,
+    record::{
+        self, Record, FLAG_BATCH_BEGIN, FLAG_BATCH_COMMIT, FLAG_LZ4, FLAG_TOMBSTONE, FLAG_ZSTD,
+    },
+};
+
+/// A data file read into memory for looking at it record by record, damaged
+/// parts included. Not meant for files of a store that is open.
+#[derive(Debug)]
+pub struct DataFile {
+    path: PathBuf,
+    version: u8,
+    bytes: Vec<u8>,
+}
+
+/// A record as found on disk
+#[derive(Debug)]
+pub struct RawRecord {
+    pub offset: u32,
+    pub size: u32,
+    pub crc_ok: bool,
+    pub flags: u8,
+    pub ts: u32,
+    pub expires_at: Option<u32>,
+    pub key: Vec<u8>,
+    /// Size of the value as stored, compressed or not
+    pub value_sz: u32,
+}
+
+impl RawRecord {
+    pub fn kind(&self) -> &'static str {
+        if self.flags & FLAG_BATCH_BEGIN != 0 {
+            return "batch-begin";
+        }
+        if self.flags & FLAG_BATCH_COMMIT != 0 {
+            return "batch-commit";
+        }
+        if self.flags & FLAG_TOMBSTONE != 0 {
+            return "tombstone";
+        }
+        return "put";
+    }
+
+    /// Name of the codec the value is compressed with
+    pub fn codec(&self) -> Option<&'static str> {
+        if self.flags & FLAG_LZ4 != 0 {
+            return Some("lz4");
+        }
+        if self.flags & FLAG_ZSTD != 0 {
+            return Some("zstd");
+        }
+        return None;
+    }
+}
+
+#[derive(Debug)]
+pub enum Entry {
+    Record(RawRecord),
+    /// Bytes that don't decode to a record, up to where a good one starts again
+    Corrupt {
+        offset: u32,
+        size: u32,
+    },
+}
+
+impl DataFile {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        let file_sz = file.metadata()?.len() as u32;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let version = record::read_version(&file, file_sz)
+            .map_err(|e| e.at(&file_name, 0))?
+            .unwrap_or(record::VERSION);
+        let bytes = fs::read(&path)?;
+        return Ok(Self {
+            path,
+            version,
+            bytes,
+        });
+    }
+
+    pub fn version(&self) -> u8 {
+        return self.version;
+    }
+
+    pub fn entries(&self) -> Entries<'_> {
+        let start = record::records_start(self.version).min(self.bytes.len() as u32);
+        return Entries {
+            file: self,
+            posi: start as usize,
+        };
+    }
+
+    /// `None` when the file has no hint, otherwise whether it passes its crc
+    pub fn hint_ok(&self) -> Result<Option<bool>> {
+        let hint_path = self.path.with_extension("hint");
+        if !hint_path.exists() {
+            return Ok(None);
+        }
+        return Ok(Some(hint::read_hint(&hint_path)?.is_some()));
+    }
+}
+
+#[derive(Debug)]
+pub struct Entries<'a> {
+    file: &'a DataFile,
+    posi: usize,
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = &self.file.bytes;
+        let posi = self.posi;
+        if posi >= bytes.len() {
+            return None;
+        }
+
+        if let Ok((record, size, crc_ok)) = Record::parse(&bytes[posi..], self.file.version) {
+            let next = posi + size;
+            // a bad crc is only trusted to be the record's own damage when its
+            // sizes lead to the start of a good record, otherwise they are garbage too
+            if crc_ok
+                || next == bytes.len()
+                || record::good_record_sz(&bytes[next..], self.file.version).is_some()
+            {
+                self.posi = next;
+                return Some(Entry::Record(RawRecord {
+                    offset: posi as u32,
+                    size: size as u32,
+                    crc_ok,
+                    flags: record.flags,
+                    ts: record.ts,
+                    expires_at: record.expires_at,
+                    key: record.key,
+                    value_sz: record.value.len() as u32,
+                }));
+            }
+        }
+
+        let next = record::next_good_record(&bytes[posi + 1..], self.file.version)
+            .map_or(bytes.len(), |p| posi + 1 + p);
+        self.posi = next;
+        return Some(Entry::Corrupt {
+            offset: posi as u32,
+            size: (next - posi) as u32,
+        });
+    }
+}
+
+/// What `repair` dropped from a file
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub kept_records: usize,
+    pub dropped_records: usize,
+    pub dropped_bytes: u64,
+}
+
+/// Rewrites the data file at `path` with only the records that pass their crc.
+/// Its hint is removed since the offsets in it no longer hold. The store must
+/// not be open while this runs.
+pub fn repair(path: impl AsRef<Path>) -> Result<RepairReport> {
+    let path = path.as_ref();
+    if path.extension().is_some() {
+        return Err(Error::invalid(format!(
+            "{} is not a data file",
+            path.display()
+        )));
+    }
+    let data_file = DataFile::open(path)?;
+
+    let header_sz = record::records_start(data_file.version) as usize;
+    let mut buf = data_file.bytes[..header_sz.min(data_file.bytes.len())].to_vec();
+    if buf.len() < header_sz {
+        // a torn header, there can't be records behind it
+        buf = record::header();
+    }
+
+    let mut report = RepairReport::default();
+    for entry in data_file.entries() {
+        match entry {
+            Entry::Record(r) if r.crc_ok => {
+                let (start, end) = (r.offset as usize, (r.offset + r.size) as usize);
+                buf.extend_from_slice(&data_file.bytes[start..end]);
+                report.kept_records += 1;
+            }
+            Entry::Record(r) => {
+                report.dropped_records += 1;
+                report.dropped_bytes += r.size as u64;
+            }
+            Entry::Corrupt { size, .. } => {
+                report.dropped_bytes += size as u64;
+            }
+        }
+    }
+
+    let tmp_path = path.with_extension("repair");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&buf)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    hint::remove_hint(&path.with_extension("hint"))?;
+    return Ok(report);
+}
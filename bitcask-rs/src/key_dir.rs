@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+};
+
+/// Where the latest record of a key is. The value is the last `value_sz`
+/// bytes of the record, as stored.
+#[derive(Debug, Clone)]
+pub struct KeyDirValue {
+    pub file_id: String,
+    pub record_posi: u32,
+    pub record_sz: u32,
+    pub value_sz: u32,
+    pub expires_at: Option<u32>,
+}
+
+impl KeyDirValue {
+    pub fn is_expired(&self, now: u32) -> bool {
+        return self.expires_at.is_some_and(|expires_at| expires_at <= now);
+    }
+}
+
+/// The in memory index from key to the location of its latest record. The
+/// ordered one costs more per lookup but can list a range without sorting
+/// every key first.
+#[derive(Debug)]
+pub enum KeyDir {
+    Hash(HashMap<Vec<u8>, KeyDirValue>),
+    Ordered(BTreeMap<Vec<u8>, KeyDirValue>),
+}
+
+impl KeyDir {
+    pub fn new(ordered: bool) -> Self {
+        return match ordered {
+            true => KeyDir::Ordered(BTreeMap::new()),
+            false => KeyDir::Hash(HashMap::new()),
+        };
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&KeyDirValue> {
+        return match self {
+            KeyDir::Hash(m) => m.get(key),
+            KeyDir::Ordered(m) => m.get(key),
+        };
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut KeyDirValue> {
+        return match self {
+            KeyDir::Hash(m) => m.get_mut(key),
+            KeyDir::Ordered(m) => m.get_mut(key),
+        };
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        return self.get(key).is_some();
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, val: KeyDirValue) -> Option<KeyDirValue> {
+        return match self {
+            KeyDir::Hash(m) => m.insert(key, val),
+            KeyDir::Ordered(m) => m.insert(key, val),
+        };
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<KeyDirValue> {
+        return match self {
+            KeyDir::Hash(m) => m.remove(key),
+            KeyDir::Ordered(m) => m.remove(key),
+        };
+    }
+
+    /// Drops the keys that expired by `now` and returns where their records are
+    pub fn remove_expired(&mut self, now: u32) -> Vec<KeyDirValue> {
+        let mut expired = vec![];
+        self.retain(|v| {
+            if v.is_expired(now) {
+                expired.push(v.clone());
+                return false;
+            }
+            return true;
+        });
+        return expired;
+    }
+
+    /// Keeps only the keys whose location `keep` accepts
+    pub fn retain(&mut self, mut keep: impl FnMut(&KeyDirValue) -> bool) {
+        match self {
+            KeyDir::Hash(m) => m.retain(|_, v| keep(v)),
+            KeyDir::Ordered(m) => m.retain(|_, v| keep(v)),
+        }
+    }
+
+    /// Every key with its location, in no particular order
+    pub fn into_entries(self) -> Vec<(Vec<u8>, KeyDirValue)> {
+        return match self {
+            KeyDir::Hash(m) => m.into_iter().collect(),
+            KeyDir::Ordered(m) => m.into_iter().collect(),
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return match self {
+            KeyDir::Hash(m) => m.len(),
+            KeyDir::Ordered(m) => m.len(),
+        };
+    }
+
+    /// Sorted keys between `lower` and `upper` that haven't expired by `now`,
+    /// at most `limit` of them. Also tells whether that was the last of them.
+    /// The hash index has to sort every matching key, so it returns all of them
+    /// in one go.
+    pub fn keys_in(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        now: u32,
+        limit: usize,
+    ) -> (Vec<Vec<u8>>, bool) {
+        let bounds = (lower.as_ref(), upper.as_ref());
+        match self {
+            KeyDir::Hash(m) => {
+                let mut keys: Vec<_> = m
+                    .iter()
+                    .filter(|(k, v)| !v.is_expired(now) && in_bounds(&bounds, k))
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                keys.sort();
+                return (keys, true);
+            }
+            KeyDir::Ordered(m) => {
+                // a range with its start past its end would make BTreeMap panic
+                if !bounds_ordered(&bounds) {
+                    return (vec![], true);
+                }
+                let mut keys = vec![];
+                let mut entries = m.range::<Vec<u8>, _>(bounds);
+                for (k, v) in entries.by_ref() {
+                    if !v.is_expired(now) {
+                        keys.push(k.clone());
+                    }
+                    if keys.len() >= limit {
+                        break;
+                    }
+                }
+                let done = entries.next().is_none();
+                return (keys, done);
+            }
+        }
+    }
+}
+
+fn in_bounds(bounds: &(Bound<&Vec<u8>>, Bound<&Vec<u8>>), key: &Vec<u8>) -> bool {
+    let above = match bounds.0 {
+        Bound::Included(lower) => key >= lower,
+        Bound::Excluded(lower) => key > lower,
+        Bound::Unbounded => true,
+    };
+    let below = match bounds.1 {
+        Bound::Included(upper) => key <= upper,
+        Bound::Excluded(upper) => key < upper,
+        Bound::Unbounded => true,
+    };
+    return above && below;
+}
+
+fn bounds_ordered(bounds: &(Bound<&Vec<u8>>, Bound<&Vec<u8>>)) -> bool {
+    return match bounds {
+        (Bound::Included(lower), Bound::Included(upper)) => lower <= upper,
+        (Bound::Included(lower) | Bound::Excluded(lower), Bound::Excluded(upper))
+        | (Bound::Excluded(lower), Bound::Included(upper)) => lower < upper,
+        _ => true,
+    };
+}
@@ -0,0 +1,47 @@
+//! Bitcask, an append-only key value store with an in memory index
+//!
+//! ```no_run
+//! use bitcask::{Options, Store};
+//!
+//! # fn main() -> bitcask::Result<()> {
+//! let store = Store::open("dbs", Options::default().create_if_missing(true))?;
+//! store.put("mac".to_string(), "a laptop".to_string())?;
+//! assert_eq!(store.get("mac".to_string())?.as_deref(), Some("a laptop"));
+//! store.delete("mac".to_string())?;
+//! store.merge_and_compact()?;
+//! # return Ok(());
+//! # }
+//! ```
+
+#![allow(clippy::needless_return)]
+
+mod batch;
+mod cdc;
+mod compactor;
+mod compression;
+mod error;
+mod hint;
+pub mod inspect;
+mod key_dir;
+mod manifest;
+mod options;
+mod record;
+mod refresher;
+mod replication;
+mod scan;
+mod snapshot;
+mod stats;
+mod store;
+mod syncer;
+mod value;
+
+pub use batch::WriteBatch;
+pub use cdc::{Change, Position, Subscription};
+pub use error::{Error, Result};
+pub use options::{Codec, CompactionPolicy, Compression, Options, SyncPolicy};
+pub use record::{MAX_KEY_SIZE, MAX_VALUE_SIZE};
+pub use replication::{serve_replicas, Replica, ReplicaServer, ReplicaStatus};
+pub use scan::{Keys, Scan};
+pub use stats::{FileStats, Stats};
+pub use store::{Recovery, Store};
+pub use value::Value;
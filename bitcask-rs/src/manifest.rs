@@ -0,0 +1,107 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::error::{Error, Result};
+
+/// Keeps the next data file id of a store, so ids are handed out only once
+/// even across crashes
+pub const MANIFEST: &str = "bitcask.manifest";
+const FORMAT: &str = "bitcask-manifest 1";
+
+/// Hands out data file ids, a sequence that only ever goes up. Ids are
+/// persisted in the manifest before a file is created with them. On disk it's
+/// text, the format line and `next <id>`.
+#[derive(Debug)]
+pub struct FileIds {
+    dir: PathBuf,
+    next: u64,
+}
+
+impl FileIds {
+    /// Reads the manifest of the store in `dir` and checks that its data files,
+    /// the newest of which is `newest`, got their ids from it. Stores from
+    /// before the manifest continue after their newest file, the manifest is
+    /// only written when `writable`.
+    pub fn open(dir: &Path, newest: Option<u64>, writable: bool) -> Result<Self> {
+        let stored = read(dir)?;
+        let next = match (stored, newest) {
+            (Some(next), Some(newest)) if newest >= next => {
+                let reason = format!("Data file is newer than the next id {next} in the manifest");
+                return Err(Error::corrupt(reason).at(&newest.to_string(), 0));
+            }
+            (Some(next), _) => next,
+            (None, newest) => newest.map_or(1, |id| id + 1),
+        };
+
+        let mut ids = Self {
+            dir: dir.to_path_buf(),
+            next,
+        };
+        if stored.is_none() && writable {
+            ids.persist(next)?;
+        }
+        return Ok(ids);
+    }
+
+    /// The next id, persisted before it is returned
+    pub fn new_id(&mut self) -> Result<String> {
+        return Ok(self.reserve(1)?.to_string());
+    }
+
+    /// Reserves `n` ids in a row and returns the first of them
+    pub fn reserve(&mut self, n: u64) -> Result<u64> {
+        let first = self.next;
+        self.persist(first + n)?;
+        return Ok(first);
+    }
+
+    /// Makes sure ids handed out from now on come after `id`, which a file got
+    /// elsewhere, like the primary of a replica
+    pub fn advance_past(&mut self, id: u64) -> Result<()> {
+        if id >= self.next {
+            self.persist(id + 1)?;
+        }
+        return Ok(());
+    }
+
+    fn persist(&mut self, next: u64) -> Result<()> {
+        write_atomically(&self.dir, MANIFEST, &format!("{FORMAT}\nnext {next}\n"))?;
+        self.next = next;
+        return Ok(());
+    }
+}
+
+/// The next id in the manifest of `dir`, `None` if there is no manifest yet
+fn read(dir: &Path) -> Result<Option<u64>> {
+    let text = match fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = text.lines();
+    if lines.next() != Some(FORMAT) {
+        return Err(Error::corrupt("Unknown manifest format").at(MANIFEST, 0));
+    }
+    return match lines.next().and_then(|line| line.strip_prefix("next ")) {
+        Some(next) => match next.parse() {
+            Ok(next) => Ok(Some(next)),
+            Err(_) => Err(Error::corrupt("Malformed next id").at(MANIFEST, 0)),
+        },
+        None => Err(Error::corrupt("Missing next id").at(MANIFEST, 0)),
+    };
+}
+
+/// Replaces `dir/name` with `text` so that a crash leaves either the old or
+/// the new content, synced to disk before it returns
+pub fn write_atomically(dir: &Path, name: &str, text: &str) -> Result<()> {
+    let tmp_path = dir.join(format!("{name}.tmp"));
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(text.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(name))?;
+    File::open(dir)?.sync_all()?;
+    return Ok(());
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+/// When appended records are forced to disk. Whatever the policy,
+/// `Store::sync` flushes everything written so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync after every write, an acknowledged write survives power loss
+    Always,
+    /// fsync from a background thread every interval, at most that much worth
+    /// of writes is lost on power loss
+    Interval(Duration),
+    /// Leave flushing to the OS, only a file the store rotated away from is
+    /// synced
+    Os,
+}
+
+/// When the background thread compacts on its own. Files are merged once
+/// enough of them is garbage, or all of them once the store grows too big.
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+    /// Share of dead bytes, between 0 and 1, from which a file gets merged
+    pub garbage_ratio: f64,
+    /// Total size of the data files above which every file holding garbage is
+    /// merged
+    pub max_disk_usage: Option<u64>,
+    /// How often the policy is checked
+    pub check_interval: Duration,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        return Self {
+            garbage_ratio: 0.5,
+            max_disk_usage: None,
+            check_interval: Duration::from_secs(60),
+        };
+    }
+}
+
+/// How values are compressed on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Lz4,
+    /// With its compression level, 0 picks zstd's default
+    Zstd(i32),
+}
+
+/// Compresses values of at least `min_value_sz` bytes with `codec`. Values
+/// that don't get smaller are stored as they are.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    pub codec: Codec,
+    pub min_value_sz: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        return Self {
+            codec: Codec::Lz4,
+            min_value_sz: 1024,
+        };
+    }
+}
+
+/// Settings for `Store::open`, built up from `Options::default()`
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub(crate) max_file_sz: u32,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) read_only: bool,
+    pub(crate) create_if_missing: bool,
+    pub(crate) ordered_index: bool,
+    pub(crate) compaction: Option<CompactionPolicy>,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) mmap: bool,
+    pub(crate) refresh_interval: Option<Duration>,
+    /// Set by `Replica::start`, the files are written by the primary's stream
+    pub(crate) replica: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        return Self {
+            max_file_sz: 4_194_304, // 4 MB
+            sync: SyncPolicy::Os,
+            read_only: false,
+            create_if_missing: false,
+            ordered_index: false,
+            compaction: None,
+            compression: None,
+            mmap: false,
+            refresh_interval: None,
+            replica: false,
+        };
+    }
+}
+
+impl Options {
+    /// Size after which the active file is closed and a new one started. It has
+    /// to leave room for records after the file header, `Store::open` fails
+    /// with `Error::Invalid` otherwise.
+    pub fn max_file_size(mut self, max_file_sz: u32) -> Self {
+        self.max_file_sz = max_file_sz;
+        return self;
+    }
+
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        return self;
+    }
+
+    /// Opens every file read only. Writes and merges are refused and nothing on
+    /// disk is modified, not even to recover a torn active file. No lock is
+    /// taken, so a read only store can be open while another process writes.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        return self;
+    }
+
+    /// Creates the data directory when it doesn't exist instead of failing
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        return self;
+    }
+
+    /// Keeps the key dir sorted, which makes `Store::range` and
+    /// `Store::scan_prefix` cheap at the cost of slower gets and puts
+    pub fn ordered_index(mut self, ordered_index: bool) -> Self {
+        self.ordered_index = ordered_index;
+        return self;
+    }
+
+    /// Compacts automatically by `policy`. Without one merges only happen when
+    /// asked for.
+    pub fn compaction(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction = Some(policy);
+        return self;
+    }
+
+    /// Compresses values written from now on. Files can mix compressed and
+    /// uncompressed records, a merge rewrites the old ones with these settings.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        return self;
+    }
+
+    /// Memory maps data files once nothing is appended to them anymore, so
+    /// reading a value from them takes neither a syscall nor a copy. The files
+    /// must not be modified from outside while the store is open.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        return self;
+    }
+
+    /// How often a read only store calls `Store::refresh` on its own to pick up
+    /// what a writer in another process did. Without one it only sees the files
+    /// as they were when opened, unless refreshed by hand.
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = Some(interval);
+        return self;
+    }
+}
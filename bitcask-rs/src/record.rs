@@ -0,0 +1,457 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::prelude::FileExt,
+    time::{Duration, SystemTime},
+};
+
+use integer_encoding::{VarInt, VarIntReader};
+
+use crate::{
+    compression,
+    error::{Error, Result},
+    options::Compression,
+};
+
+/// Every data file starts with the magic followed by a format version byte.
+/// Files written before the header existed are treated as `LEGACY_VERSION`.
+pub const MAGIC: &[u8; 4] = b"BCSK";
+pub const HEADER_SZ: u32 = MAGIC.len() as u32 + 1;
+
+/// Headerless files where deletes are the value `TOMBSTONE` and values
+/// containing it are escaped.
+pub const LEGACY_VERSION: u8 = 0;
+/// crc, flags, ts, [expires_at], key_sz, value_sz, key, value
+pub const VERSION: u8 = 1;
+
+pub const FLAG_TOMBSTONE: u8 = 1 << 0;
+/// The record carries `expires_at` right after `ts`
+pub const FLAG_EXPIRES: u8 = 1 << 1;
+/// Markers framing the records of a `WriteBatch`, they carry no key
+pub const FLAG_BATCH_BEGIN: u8 = 1 << 2;
+pub const FLAG_BATCH_COMMIT: u8 = 1 << 3;
+/// The value is stored compressed with the codec
+pub const FLAG_LZ4: u8 = 1 << 4;
+pub const FLAG_ZSTD: u8 = 1 << 5;
+const FLAGS_COMPRESSED: u8 = FLAG_LZ4 | FLAG_ZSTD;
+const KNOWN_FLAGS: u8 =
+    FLAG_TOMBSTONE | FLAG_EXPIRES | FLAG_BATCH_BEGIN | FLAG_BATCH_COMMIT | FLAGS_COMPRESSED;
+
+/// Largest key a put takes
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+/// Largest value a put takes. Positions in data files are 32 bit, with room
+/// left for a full file.
+pub const MAX_VALUE_SIZE: usize = i32::MAX as usize;
+
+const TOMBSTONE: &[u8] = b"<=>";
+const ESCAPED_TOMBSTONE: &[u8] = b"<=><=>";
+
+/// A record as it is stored, so a compressed one holds its value compressed
+#[derive(Debug)]
+pub struct Record {
+    pub flags: u8,
+    pub ts: u32,
+    /// Seconds since epoch from which the record reads as deleted, set along
+    /// with `FLAG_EXPIRES`
+    pub expires_at: Option<u32>,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        return Self {
+            flags: 0,
+            ts: now_secs(),
+            expires_at: None,
+            key,
+            value,
+        };
+    }
+
+    /// A record that expires `ttl` from now, rounded up to whole seconds
+    pub fn expiring(key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Self {
+        let mut record = Self::new(key, value);
+        let ttl_secs = ttl.as_secs() + (ttl.subsec_nanos() > 0) as u64;
+        let expires_at = (record.ts as u64 + ttl_secs).min(u32::MAX as u64) as u32;
+        record.flags |= FLAG_EXPIRES;
+        record.expires_at = Some(expires_at);
+        return record;
+    }
+
+    pub fn tombstone(key: Vec<u8>) -> Self {
+        let mut record = Self::new(key, vec![]);
+        record.flags |= FLAG_TOMBSTONE;
+        return record;
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        return self.flags & FLAG_TOMBSTONE != 0;
+    }
+
+    pub fn is_batch_begin(&self) -> bool {
+        return self.flags & FLAG_BATCH_BEGIN != 0;
+    }
+
+    pub fn is_batch_commit(&self) -> bool {
+        return self.flags & FLAG_BATCH_COMMIT != 0;
+    }
+
+    pub fn is_expired(&self, now: u32) -> bool {
+        return self.expires_at.is_some_and(|expires_at| expires_at <= now);
+    }
+
+    /// Fails for keys and values longer than `MAX_KEY_SIZE` and `MAX_VALUE_SIZE`
+    pub fn check_size(&self) -> Result<()> {
+        if self.key.len() > MAX_KEY_SIZE {
+            return Err(Error::KeyTooLarge {
+                size: self.key.len(),
+            });
+        }
+        if self.value.len() > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLarge {
+                size: self.value.len(),
+            });
+        }
+        return Ok(());
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        return self.flags & FLAGS_COMPRESSED != 0;
+    }
+
+    /// Compresses the value when `compression` applies to it. Tombstones and
+    /// batch markers are left alone.
+    pub fn compress(&mut self, compression: &Compression) -> Result<()> {
+        let is_marker = self.is_batch_begin() || self.is_batch_commit();
+        if self.is_tombstone() || is_marker || self.is_compressed() {
+            return Ok(());
+        }
+        if self.value.len() < compression.min_value_sz {
+            return Ok(());
+        }
+        let compressed = compression.codec.compress(&self.value)?;
+        if compressed.len() < self.value.len() {
+            self.value = compressed;
+            self.flags |= compression.codec.flag();
+        }
+        return Ok(());
+    }
+
+    pub fn decompress(&mut self) -> Result<()> {
+        if self.is_compressed() {
+            self.value = compression::decompress(self.flags, std::mem::take(&mut self.value))?;
+            self.flags &= !FLAGS_COMPRESSED;
+        }
+        return Ok(());
+    }
+
+    /// Everything the crc covers, laid out as `version` stores it
+    fn body(
+        version: u8,
+        flags: u8,
+        ts: u32,
+        expires_at: Option<u32>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Vec<u8> {
+        let mut buf = vec![];
+        if version != LEGACY_VERSION {
+            buf.push(flags);
+        }
+        buf.append(&mut ts.encode_var_vec());
+        if let Some(expires_at) = expires_at {
+            buf.append(&mut expires_at.encode_var_vec());
+        }
+        buf.append(&mut key.len().encode_var_vec());
+        buf.append(&mut value.len().encode_var_vec());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+        return buf;
+    }
+
+    /// Always serializes in the current `VERSION`
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Self::body(
+            VERSION,
+            self.flags,
+            self.ts,
+            self.expires_at,
+            &self.key,
+            &self.value,
+        );
+
+        let crc = calculate_checksum(&buf);
+        let mut ser = crc.encode_var_vec();
+        ser.append(&mut buf);
+        return ser;
+    }
+
+    /// Decodes the record at the start of `bytes` along with its size, and
+    /// whether it passes its crc rather than failing when it doesn't
+    pub fn parse(bytes: &[u8], version: u8) -> Result<(Self, usize, bool)> {
+        let mut buf = bytes;
+        let crc = decode_u32(&mut buf)?;
+        let crc_bytes_sz = bytes.len() - buf.len();
+
+        let mut flags = 0;
+        if version != LEGACY_VERSION {
+            let (first, rest) = buf
+                .split_first()
+                .ok_or_else(|| Error::corrupt("Failed to decode flags from bytes"))?;
+            flags = *first;
+            buf = rest;
+        }
+        let ts = decode_u32(&mut buf)?;
+        let mut expires_at = None;
+        if flags & FLAG_EXPIRES != 0 {
+            expires_at = Some(decode_u32(&mut buf)?);
+        }
+        let key_sz = decode_u32(&mut buf)? as usize;
+        let value_sz = decode_u32(&mut buf)? as usize;
+
+        if buf.len() < key_sz + value_sz {
+            return Err(Error::corrupt("Record is longer than the given bytes"));
+        }
+        let key = buf[..key_sz].to_vec();
+        let value = buf[key_sz..key_sz + value_sz].to_vec();
+
+        let total_bytes = bytes.len() - buf.len() + key_sz + value_sz;
+        let calculated_hash = calculate_checksum(&bytes[crc_bytes_sz..total_bytes]);
+
+        let record = Self::decoded(version, flags, ts, expires_at, key, value)?;
+        return Ok((record, total_bytes, crc == calculated_hash));
+    }
+
+    /// Reads the next record along with the size of its value as stored, which
+    /// differs from the decoded one for escaped values of legacy files
+    pub fn from_reader<R>(reader: &mut R, version: u8) -> Result<(Self, u32)>
+    where
+        R: VarIntReader + Read,
+    {
+        let crc = read_u32(reader)?;
+        let mut flags = 0;
+        if version != LEGACY_VERSION {
+            let mut buf = [0; 1];
+            reader.read_exact(&mut buf)?;
+            flags = buf[0];
+        }
+        let ts = read_u32(reader)?;
+        let mut expires_at = None;
+        if flags & FLAG_EXPIRES != 0 {
+            expires_at = Some(read_u32(reader)?);
+        }
+        let key_sz = read_u32(reader)?;
+        let value_sz = read_u32(reader)?;
+
+        let key = read_sized(reader, key_sz)?;
+        let value = read_sized(reader, value_sz)?;
+
+        let body = Self::body(version, flags, ts, expires_at, &key, &value);
+        if crc != calculate_checksum(&body) {
+            return Err(Error::corrupt("Calculated hash not equal to crc"));
+        }
+
+        let record = Self::decoded(version, flags, ts, expires_at, key, value)?;
+        return Ok((record, value_sz));
+    }
+
+    /// Brings a record read from a file of `version` to its in memory form
+    fn decoded(
+        version: u8,
+        flags: u8,
+        ts: u32,
+        expires_at: Option<u32>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<Self> {
+        if version == LEGACY_VERSION {
+            if value == TOMBSTONE {
+                return Ok(Self {
+                    flags: FLAG_TOMBSTONE,
+                    ts,
+                    expires_at,
+                    key,
+                    value: vec![],
+                });
+            }
+            let value = replace_bytes(&value, ESCAPED_TOMBSTONE, TOMBSTONE);
+            return Ok(Self {
+                flags,
+                ts,
+                expires_at,
+                key,
+                value,
+            });
+        }
+
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::corrupt(format!(
+                "Unknown record flags {flags:#010b}"
+            )));
+        }
+        return Ok(Self {
+            flags,
+            ts,
+            expires_at,
+            key,
+            value,
+        });
+    }
+}
+
+/// Size of the record at the start of `bytes` if it decodes and passes its crc
+pub fn good_record_sz(bytes: &[u8], version: u8) -> Option<usize> {
+    return match Record::parse(bytes, version) {
+        Ok((_, size, true)) => Some(size),
+        _ => None,
+    };
+}
+
+/// Where the first record in `bytes` that passes its crc starts, which is
+/// where reading picks up again after damaged bytes
+pub fn next_good_record(bytes: &[u8], version: u8) -> Option<usize> {
+    return (0..bytes.len()).find(|&p| good_record_sz(&bytes[p..], version).is_some());
+}
+
+/// Seconds since epoch, 0 when the clock is set before it
+pub fn now_secs() -> u32 {
+    return SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+}
+
+/// Checks the crc of the serialized record `bytes`, whose value is its last
+/// `value_sz` bytes. Returns the record's flags and where the value starts.
+pub fn check_record(bytes: &[u8], value_sz: u32, version: u8) -> Result<(u8, usize)> {
+    let mut body = bytes;
+    let crc = decode_u32(&mut body)?;
+    if crc != calculate_checksum(body) {
+        return Err(Error::corrupt("Calculated hash not equal to crc"));
+    }
+    let mut flags = 0;
+    if version != LEGACY_VERSION {
+        flags = *body
+            .first()
+            .ok_or_else(|| Error::corrupt("Failed to decode flags from bytes"))?;
+    }
+
+    let value_start = bytes
+        .len()
+        .checked_sub(value_sz as usize)
+        .ok_or_else(|| Error::corrupt("Value is longer than its record"))?;
+    return Ok((flags, value_start));
+}
+
+/// Whether values of records with `flags` are stored exactly as they were put
+pub fn is_stored_as_is(version: u8, flags: u8) -> bool {
+    return version != LEGACY_VERSION && flags & FLAGS_COMPRESSED == 0;
+}
+
+/// Turns a value as stored into the one that was put
+pub fn decode_value(version: u8, flags: u8, value: Vec<u8>) -> Result<Vec<u8>> {
+    if version == LEGACY_VERSION {
+        return Ok(replace_bytes(&value, ESCAPED_TOMBSTONE, TOMBSTONE));
+    }
+    return compression::decompress(flags, value);
+}
+
+/// Checks the crc of the serialized record `bytes` and returns its value
+/// as it was put
+pub fn checked_value(mut bytes: Vec<u8>, value_sz: u32, version: u8) -> Result<Vec<u8>> {
+    let (flags, value_start) = check_record(&bytes, value_sz, version)?;
+    let value = bytes.split_off(value_start);
+    return decode_value(version, flags, value);
+}
+
+pub fn header() -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.push(VERSION);
+    return buf;
+}
+
+/// Returns the format version of a data file, or `None` when the file is too
+/// short to tell, i.e. it is empty or its header was torn while creating it.
+pub fn read_version(file: &File, file_sz: u32) -> Result<Option<u8>> {
+    let mut buf = vec![0; file_sz.min(HEADER_SZ) as usize];
+    file.read_exact_at(&mut buf, 0)?;
+    return parse_version(&buf);
+}
+
+/// Like `read_version`, from the first bytes of a file
+pub fn parse_version(bytes: &[u8]) -> Result<Option<u8>> {
+    let buf = &bytes[..bytes.len().min(HEADER_SZ as usize)];
+    if buf.len() < HEADER_SZ as usize {
+        if MAGIC.starts_with(buf) {
+            return Ok(None);
+        }
+        return Ok(Some(LEGACY_VERSION));
+    }
+    if &buf[..MAGIC.len()] != MAGIC {
+        return Ok(Some(LEGACY_VERSION));
+    }
+
+    let version = buf[MAGIC.len()];
+    if version > VERSION {
+        return Err(Error::corrupt(format!(
+            "Unsupported data file version {version}"
+        )));
+    }
+    return Ok(Some(version));
+}
+
+/// Offset of the first record in a file of `version`
+pub fn records_start(version: u8) -> u32 {
+    if version == LEGACY_VERSION {
+        return 0;
+    }
+    return HEADER_SZ;
+}
+
+/// Byte-wise counterpart of `str::replace`
+fn replace_bytes(val: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut replaced = Vec::with_capacity(val.len());
+    let mut i = 0;
+    while i < val.len() {
+        if val[i..].starts_with(from) {
+            replaced.extend_from_slice(to);
+            i += from.len();
+        } else {
+            replaced.push(val[i]);
+            i += 1;
+        }
+    }
+    return replaced;
+}
+
+fn decode_u32(buf: &mut &[u8]) -> Result<u32> {
+    let (n, read_bytes) =
+        u32::decode_var(buf).ok_or_else(|| Error::corrupt("Failed to decode u32 from bytes"))?;
+    *buf = &buf[read_bytes..];
+    return Ok(n);
+}
+
+/// A varint that doesn't decode is damage, only running out of bytes is left
+/// as an io error
+fn read_u32<R: VarIntReader>(reader: &mut R) -> Result<u32> {
+    return reader.read_varint().map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => Error::corrupt(format!("Failed to decode u32: {e}")),
+        _ => Error::Io(e),
+    });
+}
+
+/// Reads exactly `n` bytes. The buffer grows with what is actually read, so a
+/// garbage size field can't make us allocate gigabytes before hitting EOF.
+fn read_sized<R: Read>(reader: &mut R, n: u32) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    reader.by_ref().take(n as u64).read_to_end(&mut buf)?;
+    if buf.len() != n as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reached EOF"));
+    }
+    return Ok(buf);
+}
+
+fn calculate_checksum(buf: &[u8]) -> u32 {
+    let checksum = crc32fast::hash(buf);
+    return checksum;
+}
@@ -0,0 +1,49 @@
+use std::{
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Background thread of a read only store that refreshes it every interval,
+/// picking up what the writer did meanwhile.
+#[derive(Debug)]
+pub struct Refresher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Refresher {
+    /// `refresh` returns false when the store is gone, which stops the thread
+    pub fn spawn<F>(interval: Duration, refresh: F) -> Self
+    where
+        F: Fn() -> bool + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            if !refresh() {
+                return;
+            }
+        });
+
+        return Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        };
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(t) = self.thread.take() {
+            // the last handle to the store can be the one a running refresh holds
+            if t.thread().id() != thread::current().id() {
+                t.join().unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::prelude::FileExt,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    batch::Batches,
+    cdc::Position,
+    error::{Error, Result},
+    options::Options,
+    store::{Store, WeakStore},
+};
+
+/// Bytes read from a file in one go when a replica catches up
+const CHUNK_SZ: u32 = 1 << 20;
+/// How long a replica waits before connecting again after losing the primary
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the primary checks whether to stop accepting replicas
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bytes appended to a data file of the primary, written at the same place in
+/// the replica's copy of it
+#[derive(Debug, Clone)]
+pub(crate) struct Append {
+    pub file_id: String,
+    pub offset: u32,
+    pub bytes: Arc<[u8]>,
+}
+
+/// What a store sends its replicas as it happens
+#[derive(Debug, Clone)]
+pub(crate) enum Replicated {
+    Append(Append),
+    /// A merge wrote the `outputs` files, with a handle to each, in place of the
+    /// `replaced` ones
+    Merge {
+        outputs: Vec<(String, Arc<File>)>,
+        replaced: Vec<String>,
+    },
+}
+
+/// What a replica receives after the primary's answer
+#[derive(Debug)]
+enum Message {
+    Append(Append),
+    /// Bytes of a file written by a merge
+    Merged(Append),
+    /// The files of a merge, all sent by now, take the place of the replaced ones
+    Swap {
+        outputs: Vec<String>,
+        replaced: Vec<String>,
+    },
+}
+
+const APPEND: u8 = b'A';
+const MERGED: u8 = b'M';
+const SWAP: u8 = b'S';
+
+/// What the replica wrote to its active file but couldn't index yet, a record
+/// that continues in the next append or a batch still waiting for its commit
+#[derive(Debug)]
+pub(crate) struct Tail {
+    /// Offset of `bytes` in the active file
+    pub posi: u32,
+    pub bytes: Vec<u8>,
+    pub batches: Batches,
+}
+
+impl Tail {
+    pub fn at(posi: u32) -> Self {
+        return Self {
+            posi,
+            bytes: vec![],
+            batches: Batches::default(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.bytes.is_empty() && self.batches.pending().is_none();
+    }
+
+    /// Where the file ends once what couldn't be indexed is cut off
+    pub fn complete_upto(&self) -> u32 {
+        return match self.batches.pending() {
+            Some(batch) => batch.begin_posi,
+            None => self.posi,
+        };
+    }
+}
+
+/// Sends what is appended to a store to every replica connecting on a
+/// listener, from a thread per replica, see `serve_replicas`. Dropping it
+/// stops accepting replicas, those already connected are fed until they
+/// disconnect or the store is closed.
+#[derive(Debug)]
+pub struct ReplicaServer {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ReplicaServer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Serves replicas of `store` on `listener` from a background thread, until
+/// the returned handle is dropped or the store is closed. Neither keeps the
+/// store open.
+pub fn serve_replicas(store: &Store, listener: TcpListener) -> Result<ReplicaServer> {
+    // polled, so the thread notices when to stop
+    listener.set_nonblocking(true)?;
+    let (stop, stopped) = mpsc::channel::<()>();
+    let store = store.downgrade();
+    let thread = thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((conn, peer)) => {
+                let store = store.clone();
+                thread::spawn(move || {
+                    if let Err(e) = feed_replica(&store, conn) {
+                        info!("Replica {peer} disconnected: {e}");
+                    }
+                });
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => warn!("Accepting a replica failed: {e}"),
+        }
+        match stopped.recv_timeout(ACCEPT_INTERVAL) {
+            Err(mpsc::RecvTimeoutError::Timeout) if !store.is_closed() => {}
+            _ => return,
+        }
+    });
+    return Ok(ReplicaServer {
+        stop: Some(stop),
+        thread: Some(thread),
+    });
+}
+
+/// The replica asks with `REPLICATE [<file_id> <offset>]` and gets `OK`,
+/// `GONE` when its position was merged away, or `ERR <reason>`. The bytes it
+/// misses follow, then every append and merge until either side goes away.
+fn feed_replica(store: &WeakStore, conn: TcpStream) -> Result<()> {
+    conn.set_nonblocking(false)?;
+    let mut reader = BufReader::new(conn.try_clone()?);
+    let mut writer = BufWriter::new(conn);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let bad_request = || Error::invalid(format!("Bad request {:?}", line.trim_end()));
+    let from = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["REPLICATE"] => None,
+        ["REPLICATE", file_id, offset] => Some(Position {
+            file_id: file_id.to_string(),
+            offset: offset.parse().map_err(|_| bad_request())?,
+        }),
+        _ => return Err(bad_request()),
+    };
+
+    // only held while the stream is set up, so the store can close meanwhile
+    let stream = match store.upgrade() {
+        Some(store) => store.stream_appends(from.as_ref()),
+        None => Err(Error::Closed),
+    };
+    let (replay, appends) = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            match &e {
+                Error::PositionGone(_) => writeln!(writer, "GONE")?,
+                e => writeln!(writer, "ERR {e}")?,
+            }
+            writer.flush()?;
+            return Err(e);
+        }
+    };
+    writeln!(writer, "OK")?;
+
+    for range in replay {
+        send_file(
+            &mut writer,
+            APPEND,
+            &range.file_id,
+            &range.file,
+            range.start,
+            range.end,
+        )?;
+    }
+    writer.flush()?;
+
+    // ends when the store is closed, which drops the sender
+    while let Ok(replicated) = appends.recv() {
+        send(&mut writer, replicated)?;
+        // appends that queued up meanwhile go out in one go
+        while let Ok(replicated) = appends.try_recv() {
+            send(&mut writer, replicated)?;
+        }
+        writer.flush()?;
+    }
+    return Ok(());
+}
+
+fn send(w: &mut impl Write, replicated: Replicated) -> Result<()> {
+    match replicated {
+        Replicated::Append(append) => write_append(w, APPEND, &append)?,
+        Replicated::Merge { outputs, replaced } => {
+            for (file_id, file) in outputs.iter() {
+                let end = file.metadata()?.len() as u32;
+                send_file(w, MERGED, file_id, file, 0, end)?;
+            }
+            let outputs: Vec<_> = outputs.into_iter().map(|(file_id, _)| file_id).collect();
+            write_swap(w, &outputs, &replaced)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Sends the bytes of `file` between `start` and `end` as messages of `kind`
+fn send_file(
+    w: &mut impl Write,
+    kind: u8,
+    file_id: &str,
+    file: &File,
+    start: u32,
+    end: u32,
+) -> Result<()> {
+    let mut offset = start;
+    while offset < end {
+        let mut bytes = vec![0; CHUNK_SZ.min(end - offset) as usize];
+        file.read_exact_at(&mut bytes, offset as u64)?;
+        let append = Append {
+            file_id: file_id.to_string(),
+            offset,
+            bytes: bytes.into(),
+        };
+        write_append(w, kind, &append)?;
+        offset += append.bytes.len() as u32;
+    }
+    return Ok(());
+}
+
+/// An append on the wire: its kind in one byte, the file id prefixed by its
+/// length in one byte, the offset, the number of bytes and the bytes
+/// themselves, numbers little endian
+fn write_append(w: &mut impl Write, kind: u8, append: &Append) -> io::Result<()> {
+    w.write_all(&[kind])?;
+    write_id(w, &append.file_id)?;
+    w.write_all(&append.offset.to_le_bytes())?;
+    w.write_all(&(append.bytes.len() as u32).to_le_bytes())?;
+    w.write_all(&append.bytes)?;
+    return Ok(());
+}
+
+/// A swap on the wire: its kind, then the output and the replaced file ids,
+/// each list prefixed by its length
+fn write_swap(w: &mut impl Write, outputs: &[String], replaced: &[String]) -> io::Result<()> {
+    w.write_all(&[SWAP])?;
+    for ids in [outputs, replaced] {
+        w.write_all(&(ids.len() as u32).to_le_bytes())?;
+        for id in ids {
+            write_id(w, id)?;
+        }
+    }
+    return Ok(());
+}
+
+fn write_id(w: &mut impl Write, file_id: &str) -> io::Result<()> {
+    w.write_all(&[file_id.len() as u8])?;
+    w.write_all(file_id.as_bytes())?;
+    return Ok(());
+}
+
+fn read_message(r: &mut impl Read) -> Result<Message> {
+    let mut kind = [0; 1];
+    r.read_exact(&mut kind)?;
+    return match kind[0] {
+        APPEND => Ok(Message::Append(read_append(r)?)),
+        MERGED => Ok(Message::Merged(read_append(r)?)),
+        SWAP => Ok(Message::Swap {
+            outputs: read_ids(r)?,
+            replaced: read_ids(r)?,
+        }),
+        kind => Err(Error::invalid(format!("Unknown message kind {kind}"))),
+    };
+}
+
+fn read_append(r: &mut impl Read) -> Result<Append> {
+    let file_id = read_id(r)?;
+    let mut num = [0; 4];
+    r.read_exact(&mut num)?;
+    let offset = u32::from_le_bytes(num);
+    r.read_exact(&mut num)?;
+    let mut bytes = vec![0; u32::from_le_bytes(num) as usize];
+    r.read_exact(&mut bytes)?;
+    return Ok(Append {
+        file_id,
+        offset,
+        bytes: bytes.into(),
+    });
+}
+
+fn read_ids(r: &mut impl Read) -> Result<Vec<String>> {
+    let mut num = [0; 4];
+    r.read_exact(&mut num)?;
+    return (0..u32::from_le_bytes(num)).map(|_| read_id(r)).collect();
+}
+
+fn read_id(r: &mut impl Read) -> Result<String> {
+    let mut len = [0; 1];
+    r.read_exact(&mut len)?;
+    let mut file_id = vec![0; len[0] as usize];
+    r.read_exact(&mut file_id)?;
+    return String::from_utf8(file_id).map_err(|_| Error::invalid("File id isn't UTF-8"));
+}
+
+/// A store that copies every byte a primary appends into its own files, from
+/// a background thread that reconnects whenever the connection drops, see
+/// `Replica::status`. Merges of the primary are copied too, the replica swaps
+/// in the same merged files. It continues from the end of its last file, which
+/// a merge of the primary while it was away can have replaced. Reads are
+/// served from the copy, writes and merges are refused until it is promoted.
+#[derive(Debug)]
+pub struct Replica {
+    store: Store,
+    status: Arc<Mutex<ReplicaStatus>>,
+    stop: Option<mpsc::Sender<()>>,
+    /// The current connection, shut down to stop the thread waiting on it
+    conn: Arc<Mutex<Option<TcpStream>>>,
+    thread: Option<JoinHandle<Tail>>,
+}
+
+/// Where a replica is at with its primary, see `Replica::status`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaStatus {
+    /// Connecting to the primary, again after losing it
+    Connecting,
+    /// Receiving what the primary appends
+    Following,
+    /// Gave up, the primary merged away the file the replica stopped in or has
+    /// less of it. The replica can still be read from or promoted, following
+    /// the primary again takes a replica in an empty directory.
+    PositionGone(Position),
+}
+
+impl Replica {
+    /// Opens the store at `path` as a replica of the primary serving replicas
+    /// on `primary`, see `serve_replicas`
+    pub fn start(path: impl AsRef<Path>, primary: &str, mut options: Options) -> Result<Self> {
+        if options.read_only {
+            return Err(Error::invalid("A replica can't be opened read only"));
+        }
+        options.replica = true;
+        let store = Store::open(path, options)?;
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let conn = Arc::new(Mutex::new(None));
+        let status = Arc::new(Mutex::new(ReplicaStatus::Connecting));
+        let thread = {
+            let (store, primary) = (store.clone(), primary.to_string());
+            let (conn, status) = (conn.clone(), status.clone());
+            thread::spawn(move || replicate(&store, &primary, &stopped, &conn, &status))
+        };
+        return Ok(Self {
+            store,
+            status,
+            stop: Some(stop),
+            conn,
+            thread: Some(thread),
+        });
+    }
+
+    /// The local copy, to read from
+    pub fn store(&self) -> &Store {
+        return &self.store;
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        return self.status.lock().unwrap().clone();
+    }
+
+    /// Stops following the primary and makes the store writable, continuing
+    /// in a file of its own. A record the primary was still sending is cut off.
+    pub fn promote(mut self) -> Result<Store> {
+        let tail = self.stop_thread();
+        if let Some(tail) = tail {
+            self.store.promote(tail)?;
+        }
+        return Ok(self.store.clone());
+    }
+
+    fn stop_thread(&mut self) -> Option<Tail> {
+        drop(self.stop.take());
+        if let Some(conn) = self.conn.lock().unwrap().take() {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+        return self.thread.take().map(|t| t.join().unwrap());
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+fn is_stopped(stopped: &mpsc::Receiver<()>) -> bool {
+    return matches!(stopped.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+}
+
+/// Follows the primary until stopped or its position is gone, handing back
+/// what the active file holds beyond its last complete record
+fn replicate(
+    store: &Store,
+    primary: &str,
+    stopped: &mpsc::Receiver<()>,
+    conn: &Mutex<Option<TcpStream>>,
+    status: &Mutex<ReplicaStatus>,
+) -> Tail {
+    let mut tail = Tail::at(store.replica_position().map_or(0, |p| p.offset));
+    loop {
+        match follow(store, primary, stopped, conn, status, &mut tail) {
+            // asking again would only be refused again
+            Err(Error::PositionGone(from)) => {
+                let (file_id, offset) = (&from.file_id, from.offset);
+                error!("Replicating from {primary} stopped, it no longer has {file_id}:{offset}");
+                *status.lock().unwrap() = ReplicaStatus::PositionGone(from);
+                return tail;
+            }
+            Err(e) if !is_stopped(stopped) => {
+                warn!("Replicating from {primary} failed, retrying: {e}");
+            }
+            _ => {}
+        }
+        *status.lock().unwrap() = ReplicaStatus::Connecting;
+        match stopped.recv_timeout(RETRY_INTERVAL) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            _ => return tail,
+        }
+    }
+}
+
+fn follow(
+    store: &Store,
+    primary: &str,
+    stopped: &mpsc::Receiver<()>,
+    conn: &Mutex<Option<TcpStream>>,
+    status: &Mutex<ReplicaStatus>,
+    tail: &mut Tail,
+) -> Result<()> {
+    let stream = TcpStream::connect(primary)?;
+    {
+        // checked under the lock, so stopping either sees this connection or
+        // this sees the stop
+        let mut conn = conn.lock().unwrap();
+        if is_stopped(stopped) {
+            return Ok(());
+        }
+        *conn = Some(stream.try_clone()?);
+    }
+
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let from = store.replica_position();
+    match &from {
+        Some(from) => writeln!(writer, "REPLICATE {} {}", from.file_id, from.offset)?,
+        None => writeln!(writer, "REPLICATE")?,
+    }
+    writer.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    match (line.trim_end(), from) {
+        ("OK", _) => {}
+        ("GONE", Some(from)) => return Err(Error::PositionGone(from)),
+        (refused, _) => return Err(Error::invalid(format!("Primary refused: {refused}"))),
+    }
+    *status.lock().unwrap() = ReplicaStatus::Following;
+    info!("Replicating from {primary}");
+
+    loop {
+        match read_message(&mut reader)? {
+            Message::Append(append) => store.apply_append(tail, &append)?,
+            Message::Merged(append) => store.write_merged(&append)?,
+            Message::Swap { outputs, replaced } => store.apply_merge(&outputs, &replaced)?,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::VecDeque, ops::Bound};
+
+use crate::{error::Result, store::Store};
+
+/// Keys are taken from the index this many at a time, so the lock is never
+/// held for long and a scan that stops early doesn't copy every key
+const PAGE_SZ: usize = 1024;
+
+/// Walks the keys of a range in order. Keys put after the cursor moved past
+/// them are missed, keys deleted before it gets to them are skipped.
+#[derive(Debug)]
+struct Cursor {
+    store: Store,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    page: VecDeque<Vec<u8>>,
+    done: bool,
+}
+
+impl Cursor {
+    fn next_key(&mut self) -> Option<Vec<u8>> {
+        if self.page.is_empty() && !self.done {
+            let (keys, done) = self.store.keys_in(&self.lower, &self.upper, PAGE_SZ);
+            if let Some(last) = keys.last() {
+                self.lower = Bound::Excluded(last.clone());
+            }
+            self.page = keys.into();
+            self.done = done;
+        }
+        return self.page.pop_front();
+    }
+}
+
+/// Returned by `Store::keys`
+#[derive(Debug)]
+pub struct Keys {
+    cursor: Cursor,
+}
+
+impl Iterator for Keys {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.cursor.next_key();
+    }
+}
+
+/// Returned by `Store::scan_prefix` and `Store::range`. Values are read from
+/// the data files as the iterator gets to them.
+#[derive(Debug)]
+pub struct Scan {
+    cursor: Cursor,
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.cursor.next_key()?;
+            match self.cursor.store.get_bytes(&key) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // deleted or expired since the key was listed
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn cursor(store: Store, lower: Bound<Vec<u8>>, upper: Bound<Vec<u8>>) -> Cursor {
+    return Cursor {
+        store,
+        lower,
+        upper,
+        page: VecDeque::new(),
+        done: false,
+    };
+}
+
+pub fn keys(store: Store) -> Keys {
+    return Keys {
+        cursor: cursor(store, Bound::Unbounded, Bound::Unbounded),
+    };
+}
+
+pub fn range(store: Store, lower: Bound<Vec<u8>>, upper: Bound<Vec<u8>>) -> Scan {
+    return Scan {
+        cursor: cursor(store, lower, upper),
+    };
+}
+
+/// The smallest key after every key starting with `prefix`, `None` when
+/// there is none because the prefix is all 0xff
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    return None;
+}
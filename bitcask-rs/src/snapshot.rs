@@ -0,0 +1,113 @@
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    os::unix::prelude::MetadataExt,
+    path::Path,
+};
+
+use crate::{
+    error::{Error, Result},
+    manifest,
+};
+
+/// Written last into a snapshot, so a snapshot without one is incomplete
+pub const MANIFEST: &str = "snapshot.manifest";
+const FORMAT: &str = "bitcask-snapshot 1";
+
+/// What a snapshot holds. On disk it's text, the format line, `created <secs>`
+/// and a `file <id> <size>` line per data file, oldest first.
+#[derive(Debug)]
+pub struct Manifest {
+    /// Seconds since epoch when the active file was rotated for the snapshot
+    pub created_at: u32,
+    pub files: Vec<(String, u64)>,
+}
+
+impl Manifest {
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let mut text = format!("{FORMAT}\ncreated {}\n", self.created_at);
+        for (id, size) in self.files.iter() {
+            text.push_str(&format!("file {id} {size}\n"));
+        }
+
+        return manifest::write_atomically(dir, MANIFEST, &text);
+    }
+
+    /// Reads the manifest of the snapshot in `dir` and checks that every file
+    /// it lists is there in full
+    pub fn read(dir: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(dir.join(MANIFEST)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Error::invalid(format!(
+                    "{} is not a complete snapshot",
+                    dir.display()
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(FORMAT) {
+            return Err(Error::invalid(format!(
+                "Unknown snapshot format in {}",
+                dir.display()
+            )));
+        }
+
+        let mut manifest = Manifest {
+            created_at: 0,
+            files: vec![],
+        };
+        for line in lines {
+            let malformed = || Error::invalid(format!("Malformed snapshot manifest line: {line}"));
+            let fields: Vec<_> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["created", secs] => manifest.created_at = secs.parse().map_err(|_| malformed())?,
+                ["file", id, size] => {
+                    let size = size.parse().map_err(|_| malformed())?;
+                    manifest.files.push((id.to_string(), size));
+                }
+                _ => return Err(malformed()),
+            }
+        }
+
+        for (id, size) in manifest.files.iter() {
+            let actual = match fs::metadata(dir.join(id)) {
+                Ok(metadata) => metadata.size(),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Err(Error::invalid(format!("Snapshot file {id} is missing")))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if actual != *size {
+                return Err(Error::invalid(format!(
+                    "Snapshot file {id} has {actual} bytes instead of {size}"
+                )));
+            }
+        }
+        return Ok(manifest);
+    }
+}
+
+/// Creates `dir` unless it exists, in which case it has to be empty
+pub fn create_empty_dir(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        fs::create_dir_all(dir)?;
+        return Ok(());
+    }
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(Error::invalid(format!("{} is not empty", dir.display())));
+    }
+    return Ok(());
+}
+
+/// Hard links `src` as `dest`, or copies it when it can't be linked, e.g. when
+/// `dest` is on another file system
+pub fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        let mut copy = File::create(dest)?;
+        std::io::copy(&mut File::open(src)?, &mut copy)?;
+        copy.sync_all()?;
+    }
+    return Ok(());
+}
@@ -0,0 +1,41 @@
+/// Byte counts of one data file. Dead bytes belong to records that were
+/// overwritten or deleted since, and are what a merge gets rid of.
+#[derive(Debug, Clone)]
+pub struct FileStats {
+    pub file_id: String,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl FileStats {
+    /// Share of the file that is dead, between 0 and 1
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        return self.dead_bytes as f64 / self.total_bytes as f64;
+    }
+}
+
+/// Returned by `Store::stats`, files are ordered oldest first with the active
+/// file last.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub keys: usize,
+    pub files: Vec<FileStats>,
+}
+
+impl Stats {
+    pub fn total_bytes(&self) -> u64 {
+        return self.files.iter().map(|f| f.total_bytes).sum();
+    }
+
+    pub fn live_bytes(&self) -> u64 {
+        return self.files.iter().map(|f| f.live_bytes).sum();
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        return self.files.iter().map(|f| f.dead_bytes).sum();
+    }
+}
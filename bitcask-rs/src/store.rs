@@ -1,308 +1,2031 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
     fs::{self, OpenOptions},
-    io::{BufReader, Seek, Write},
+    io::{self, BufReader, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
     os::unix::prelude::{FileExt, MetadataExt},
-    path::Path,
-    time::{SystemTime, UNIX_EPOCH},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, OnceLock, RwLock, Weak,
+    },
+    time::Duration,
 };
 
-use anyhow::Result;
+use log::{error, info, warn};
+use memmap2::Mmap;
 
-use crate::Record;
+use crate::{
+    batch::{self, Batches, WriteBatch},
+    cdc::{Change, Position, ReplayRange, Subscription},
+    compactor::{Compactor, Trigger},
+    error::{Error, Result},
+    hint::{self, HintEntry},
+    key_dir::{KeyDir, KeyDirValue},
+    manifest::FileIds,
+    options::{CompactionPolicy, Options, SyncPolicy},
+    record::{self, Record, HEADER_SZ, VERSION},
+    refresher::Refresher,
+    replication::{Append, Replicated, Tail},
+    scan::{self, Keys, Scan},
+    snapshot::{self, Manifest},
+    stats::{FileStats, Stats},
+    syncer::Syncer,
+    value::Value,
+};
 
-const DIR: &str = "dbs/";
-const MAX_FILE_SZ: u32 = 41_94_304; // 4 MB
-const TOMBSTONE: &str = "<=>";
-const ESCAPED_TOMBSTONE: &str = "<=><=>";
+fn parse_id(id: &str) -> Result<u64> {
+    return id
+        .parse()
+        .map_err(|_| Error::corrupt("Data file name is not a number").at(id, 0));
+}
 
-fn escape_tombstone(val: String) -> String {
-    return val.replace(TOMBSTONE, ESCAPED_TOMBSTONE);
+/// Files are ordered by their ids as numbers
+fn file_id_num(id: &str) -> u64 {
+    return id.parse().unwrap_or(0);
 }
 
-fn unescape_tombstone(val: String) -> String {
-    return val.replace(ESCAPED_TOMBSTONE, TOMBSTONE);
+fn hint_path(dir: &Path, id: &str) -> PathBuf {
+    return dir.join(format!("{id}.hint"));
 }
 
-fn get_sortable_id() -> String {
-    return SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        .to_string();
+/// Opens the data file `id`, creating it with a header in the current format
+/// if it doesn't exist yet
+fn open_file(dir: &Path, id: &String) -> Result<fs::File> {
+    return open_path(&dir.join(id));
 }
 
-fn open_file(id: &String) -> Result<fs::File> {
-    let file_name = format!("{DIR}/{id}");
-    let file = OpenOptions::new()
+fn open_path(file_name: &Path) -> Result<fs::File> {
+    let mut file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(file_name)?;
+    if file.metadata()?.size() == 0 {
+        file.write_all(&record::header())?;
+    }
     return Ok(file);
 }
 
+/// A record may be torn when it was cut short by the end of the file, or when
+/// it fails its checksum and nothing follows it. It only is when no good record
+/// comes after it either, which `load` checks.
+fn is_torn_tail(e: &Error, read_upto: u32, file_sz: u32) -> bool {
+    if let Error::Io(e) = e {
+        return e.kind() == io::ErrorKind::UnexpectedEof;
+    }
+    return read_upto >= file_sz;
+}
+
 #[derive(Debug)]
 struct FilWithId {
     id: String,
     file: fs::File,
+    version: u8,
+    path: PathBuf,
+    /// Set once a merge replaced the file, it is deleted when the last reader
+    /// holding on to it is done
+    obsolete: AtomicBool,
+    /// Bytes of records in this file that were overwritten or deleted since
+    dead_bytes: AtomicU64,
+    /// Set with `Options::mmap` once nothing is appended to the file anymore
+    mmap: OnceLock<Arc<Mmap>>,
+    /// The next newer file replaced by the same merge, kept until this one is
+    /// deleted. Replaced files thus go oldest first, so a process loading the
+    /// store meanwhile never sees a dropped tombstone's key still put in an
+    /// older file.
+    newer_replaced: OnceLock<Arc<FilWithId>>,
 }
 
-#[derive(Debug)]
-struct KeyDirValue {
-    file_id: String,
+impl FilWithId {
+    fn new(dir: &Path, id: String, file: fs::File, version: u8) -> Self {
+        return Self {
+            path: dir.join(&id),
+            id,
+            file,
+            version,
+            obsolete: AtomicBool::new(false),
+            dead_bytes: AtomicU64::new(0),
+            mmap: OnceLock::new(),
+            newer_replaced: OnceLock::new(),
+        };
+    }
+
+    /// Maps the file into memory for reads. Only for files that are no longer
+    /// written to, reads fall back to `read_at` when mapping fails.
+    fn map(&self) {
+        // SAFETY: data files aren't modified once they are immutable, as long as
+        // nothing outside the store writes to them while it's open
+        match unsafe { Mmap::map(&self.file) } {
+            Ok(mmap) => {
+                let _ = self.mmap.set(Arc::new(mmap));
+            }
+            Err(e) => warn!("Failed to map file {}: {e}", self.id),
+        }
+    }
+
+    fn stats(&self) -> Result<FileStats> {
+        let total_bytes = self.file.metadata()?.size();
+        let dead_bytes = self.dead_bytes.load(Ordering::Relaxed);
+        let overhead = record::records_start(self.version) as u64 + dead_bytes;
+        return Ok(FileStats {
+            file_id: self.id.clone(),
+            total_bytes,
+            live_bytes: total_bytes.saturating_sub(overhead),
+            dead_bytes,
+        });
+    }
+}
+
+/// Counts the record `val` points at as dead in the file holding it
+fn mark_dead(files: &[Arc<FilWithId>], val: &KeyDirValue) {
+    add_dead(files, &val.file_id, val.record_sz);
+}
+
+fn add_dead(files: &[Arc<FilWithId>], file_id: &str, bytes: u32) {
+    if let Some(f) = files.iter().find(|f| f.id == file_id) {
+        f.dead_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Points the key at a record just written, or drops it for a tombstone.
+/// `value_sz` is the size of the value as stored.
+fn index_record(
+    key_dir: &mut KeyDir,
+    files: &[Arc<FilWithId>],
+    file_id: &str,
+    record: Record,
+    record_posi: u32,
+    record_sz: u32,
     value_sz: u32,
-    value_posi: u32,
+) {
+    let old = match record.is_tombstone() {
+        true => key_dir.remove(&record.key),
+        false => key_dir.insert(
+            record.key,
+            KeyDirValue {
+                file_id: file_id.to_string(),
+                value_sz,
+                record_posi,
+                record_sz,
+                expires_at: record.expires_at,
+            },
+        ),
+    };
+    if let Some(old) = old {
+        mark_dead(files, &old);
+    }
 }
 
+/// Rebuilds the key dir while the store is opened, along with the dead bytes
+/// of every file read so far. Tombstones don't count as dead, they still
+/// shadow the keys they deleted in older files.
+struct Loader {
+    key_dir: KeyDir,
+    dead: HashMap<String, u64>,
+    now: u32,
+    /// Keys deleted or expired by the files read, kept when they are loaded on
+    /// top of the key dir of older ones, see `Store::refresh`
+    deleted: Option<HashSet<Vec<u8>>>,
+}
+
+impl Loader {
+    fn new(options: &Options, keep_deleted: bool) -> Self {
+        return Self {
+            key_dir: KeyDir::new(options.ordered_index),
+            dead: HashMap::new(),
+            now: record::now_secs(),
+            deleted: keep_deleted.then(HashSet::new),
+        };
+    }
+
+    fn insert(&mut self, key: Vec<u8>, val: KeyDirValue) {
+        if let Some(deleted) = &mut self.deleted {
+            deleted.remove(&key);
+        }
+        let old = self.key_dir.insert(key, val);
+        self.retire(old);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(deleted) = &mut self.deleted {
+            deleted.insert(key.to_vec());
+        }
+        let old = self.key_dir.remove(key);
+        self.retire(old);
+    }
+
+    fn add_dead(&mut self, file_id: &str, bytes: u32) {
+        *self.dead.entry(file_id.to_string()).or_default() += bytes as u64;
+    }
+
+    fn retire(&mut self, old: Option<KeyDirValue>) {
+        if let Some(old) = old {
+            self.add_dead(&old.file_id, old.record_sz);
+        }
+    }
+
+    /// `value_sz` is the size of the value as stored
+    fn load_record(
+        &mut self,
+        file_id: &str,
+        record: Record,
+        posi: u32,
+        record_sz: u32,
+        value_sz: u32,
+    ) {
+        if record.is_tombstone() {
+            self.remove(&record.key);
+            return;
+        }
+        // an expired record reads like a delete and is garbage itself
+        if record.is_expired(self.now) {
+            self.remove(&record.key);
+            self.add_dead(file_id, record_sz);
+            return;
+        }
+        let val = KeyDirValue {
+            file_id: file_id.to_string(),
+            record_posi: posi,
+            record_sz,
+            value_sz,
+            expires_at: record.expires_at,
+        };
+        self.insert(record.key, val);
+    }
+
+    fn load_hint_entry(&mut self, file_id: &str, entry: HintEntry) {
+        if entry.is_tombstone() {
+            self.remove(&entry.key);
+            return;
+        }
+        if entry.is_expired(self.now) {
+            self.remove(&entry.key);
+            self.add_dead(file_id, entry.record_sz);
+            return;
+        }
+        let val = KeyDirValue {
+            file_id: file_id.to_string(),
+            value_sz: entry.value_sz,
+            record_posi: entry.record_posi,
+            record_sz: entry.record_sz,
+            expires_at: entry.expires_at,
+        };
+        self.insert(entry.key, val);
+    }
+}
+
+impl Drop for FilWithId {
+    fn drop(&mut self) {
+        if !self.obsolete.load(Ordering::Acquire) {
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove merged file {}: {e}", self.id);
+        }
+        if let Err(e) = hint::remove_hint(&self.path.with_extension("hint")) {
+            warn!("Failed to remove hint of merged file {}: {e}", self.id);
+        }
+    }
+}
+
+/// A file a merge is writing into. It is named `<id>.merge` until the merge
+/// is done so that an interrupted merge never leaves a half written data file.
+struct MergeOutput {
+    id: String,
+    file: fs::File,
+    posi: u32,
+    hint_entries: Vec<HintEntry>,
+}
+
+/// Describes the torn tail that was cut off the active file while opening the
+/// store, usually left behind by a crash in the middle of a write.
+#[derive(Debug, Clone)]
+pub struct Recovery {
+    pub file_id: String,
+    /// Offset of the last good record boundary, the file now ends here
+    pub offset: u32,
+    pub discarded_bytes: u32,
+}
+
+/// State only the writer touches, behind a single lock so appends are serialized
 #[derive(Debug)]
-pub struct Store {
-    key_dir: HashMap<String, KeyDirValue>,
-    files: Vec<FilWithId>,
-    active_file_id: String,
+struct Writer {
+    /// `None` when the store is read only, or a replica that got no file yet
+    active: Option<Arc<FilWithId>>,
     cur_posi: u32,
+    syncer: Option<Syncer>,
+    ids: FileIds,
 }
 
-impl Store {
-    pub fn new() -> Result<Self> {
-        let dir_path = Path::new(DIR);
-        let mut paths: Vec<_> = fs::read_dir(dir_path)?
-            .filter_map(|e| {
-                if let Ok(e) = e {
-                    let path = e.path();
-                    if path.is_file() {
-                        return path.file_name().map(|el| el.to_string_lossy().to_string());
-                    }
-                }
-                return None;
+impl Writer {
+    fn active(&self) -> Result<&Arc<FilWithId>> {
+        return self.active.as_ref().ok_or(Error::ReadOnly);
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    dir: PathBuf,
+    options: Options,
+    /// Lock order is key_dir, files, so a reader holding an entry can always
+    /// still find the file it points into
+    key_dir: RwLock<KeyDir>,
+    files: RwLock<Vec<Arc<FilWithId>>>,
+    writer: Mutex<Writer>,
+    recovery: Option<Recovery>,
+    /// Held for the duration of a merge, only one runs at a time. Holds the
+    /// files the last merge replaced, which are gone once nobody uses them.
+    merging: Mutex<Vec<Weak<FilWithId>>>,
+    /// `None` when the store is read only
+    compactor: Option<Compactor>,
+    listing: Mutex<Listing>,
+    /// Where puts and deletes are sent for `Store::subscribe`
+    subscribers: Mutex<Vec<mpsc::Sender<Change>>>,
+    /// Where appended bytes are sent for replicas
+    appends: Mutex<Vec<mpsc::Sender<Replicated>>>,
+    /// Set while the store follows a primary, until it is promoted
+    replica: AtomicBool,
+    /// Set for read only stores with `Options::refresh_interval`
+    _refresher: Option<Refresher>,
+    /// Lock file of a writable store, the lock goes with it
+    _lock: Option<fs::File>,
+}
+
+/// Handle to an open store. Clones share the same store and can be sent to
+/// other threads, gets run concurrently while puts and deletes are serialized.
+#[derive(Debug, Clone)]
+pub struct Store {
+    inner: Arc<Inner>,
+}
+
+/// Handle that doesn't keep the store open, for threads that should end with it
+#[derive(Debug, Clone)]
+pub(crate) struct WeakStore {
+    inner: Weak<Inner>,
+}
+
+impl WeakStore {
+    /// The store, unless every `Store` handle was dropped
+    pub fn upgrade(&self) -> Option<Store> {
+        return self.inner.upgrade().map(|inner| Store { inner });
+    }
+
+    /// Whether every `Store` handle was dropped
+    pub fn is_closed(&self) -> bool {
+        return self.inner.strong_count() == 0;
+    }
+}
+
+/// Taken by writable stores for as long as they are open, so only one process
+/// at a time appends to the active file
+const LOCK_FILE: &str = "bitcask.lock";
+
+fn lock_dir(dir: &Path) -> Result<fs::File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(fs::TryLockError::WouldBlock) => {
+            return Err(Error::Locked {
+                dir: dir.to_path_buf(),
             })
-            .collect();
+        }
+        Err(fs::TryLockError::Error(e)) => return Err(e.into()),
+    }
+}
+
+/// Data files of the store and their sizes, sorted by id
+fn list_data_files(dir: &Path) -> Result<Vec<(String, u64)>> {
+    let mut listing = vec![];
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        let path = e.path();
+        // hints and other companion files carry an extension, data files don't
+        if path.extension().is_some() {
+            continue;
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            // removed by a merge since it was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_file() {
+            listing.push((e.file_name().to_string_lossy().to_string(), metadata.len()));
+        }
+    }
+    listing.sort_by_key(|(id, _)| file_id_num(id));
+    return Ok(listing);
+}
+
+fn check_options(options: &Options) -> Result<()> {
+    if options.max_file_sz <= HEADER_SZ {
+        return Err(Error::invalid(format!(
+            "Max file size of {} bytes leaves no room after the {HEADER_SZ} byte header",
+            options.max_file_sz
+        )));
+    }
+    return Ok(());
+}
+
+/// Data files as of the last load, to tell what a refresh has to pick up
+#[derive(Debug)]
+struct Listing {
+    /// Ids and sizes, sorted by id
+    files: Vec<(String, u64)>,
+    /// Where loading the last file stopped, a record or batch the writer was
+    /// still appending starts there
+    end: u32,
+}
+
+impl Listing {
+    /// Whether `files` only adds to what was loaded, the last file having grown
+    /// or newer ones having shown up. When a merge deleted files instead, or
+    /// added its outputs before the active file, everything is loaded again.
+    fn is_extended_by(&self, files: &[(String, u64)]) -> bool {
+        let Some(((last_id, last_sz), older)) = self.files.split_last() else {
+            return false;
+        };
+        return files.len() >= self.files.len()
+            && files[..older.len()] == *older
+            && files[older.len()].0 == *last_id
+            && files[older.len()].1 >= *last_sz;
+    }
+}
+
+/// The files of a store and the key dir read from them
+struct Loaded {
+    files: Vec<FilWithId>,
+    key_dir: KeyDir,
+    /// Keys the files deleted, when the loader kept them
+    deleted: Option<HashSet<Vec<u8>>>,
+    recovery: Option<Recovery>,
+    /// Where reading the last file stopped
+    end: u32,
+}
+
+/// Lists the data files and loads them. A writer in another process can merge
+/// away a file in between, in which case it starts over.
+fn load_consistent(dir: &Path, options: &Options) -> Result<(Listing, Loaded)> {
+    let mut attempts = 0;
+    loop {
+        let files = list_data_files(dir)?;
+        match load(dir, &files, 0, Loader::new(options, false), options) {
+            Err(e) if attempts < 3 && is_not_found(&e) => attempts += 1,
+            loaded => {
+                let loaded = loaded?;
+                let end = loaded.end;
+                return Ok((Listing { files, end }, loaded));
+            }
+        }
+    }
+}
 
-        println!("Paths: {:?}", paths);
-        paths.sort_by(|a, b| a.cmp(b));
+fn is_not_found(e: &Error) -> bool {
+    return e.is_io(io::ErrorKind::NotFound);
+}
 
-        let mut files: Vec<FilWithId> = vec![];
-        let mut active_file_id: Option<String> = None;
-        let mut key_dir: HashMap<String, KeyDirValue> = HashMap::new();
-        let n_paths = paths.len();
+/// Opens the data files in `listing` and rebuilds the key dir from them with
+/// `loader`, starting at offset `from` of the first one when it isn't 0. The
+/// last one is the active file, which is recovered when it is writable.
+fn load(
+    dir: &Path,
+    listing: &[(String, u64)],
+    from: u32,
+    mut loader: Loader,
+    options: &Options,
+) -> Result<Loaded> {
+    let mut files: Vec<FilWithId> = vec![];
+    let mut recovery: Option<Recovery> = None;
+    let mut end = 0;
+    for (i, (path, _)) in listing.iter().enumerate() {
+        let from = if i == 0 { from } else { 0 };
+        let path = path.clone();
+        let is_active = i == listing.len() - 1;
+        let is_writable = is_active && !options.read_only;
 
-        for (i, path) in paths.into_iter().enumerate() {
-            active_file_id = Some(path.clone());
+        let mut open_options = OpenOptions::new();
+        // active file will be at last index so open it with write perms
+        if is_writable {
+            open_options.write(true);
+        }
+
+        let file = open_options.read(true).open(dir.join(&path))?;
+        let mut file_sz = file.metadata()?.size() as u32;
+
+        let version = record::read_version(&file, file_sz).map_err(|e| e.at(&path, 0))?;
+        let version = match version {
+            Some(version) => version,
+            // the header is the first thing written to a new file, so without a
+            // complete one there can't be any records either
+            None if is_writable => {
+                file.set_len(0)?;
+                file.write_all_at(&record::header(), 0)?;
+                file_sz = HEADER_SZ;
+                VERSION
+            }
+            None => VERSION,
+        };
 
-            let mut open_options = OpenOptions::new();
-            // active file will be at last index so open it with write perms
-            if i == n_paths - 1 {
-                open_options.write(true);
+        // immutable files written by a merge come with a hint, which lets us skip
+        // reading the values entirely
+        if !is_active && from == 0 {
+            if let Some(entries) = hint::read_hint(&hint_path(dir, &path))? {
+                for entry in entries {
+                    loader.load_hint_entry(&path, entry);
+                }
+                files.push(FilWithId::new(dir, path, file, version));
+                continue;
             }
+        }
 
-            let file = open_options.read(true).open(format!("{DIR}{path}"))?;
-            let file_sz = file.metadata()?.size() as u32;
-            let mut reader = BufReader::new(&file);
+        let mut reader = BufReader::new(&file);
+        let start = from.max(record::records_start(version));
+        reader.seek(SeekFrom::Start(start as u64))?;
+        let mut batches = Batches::default();
+        // the begin marker of the pending batch, garbage once the batch is done
+        let mut begin_sz = 0;
+        end = file_sz;
 
-            loop {
-                let cur_posi = reader.seek(std::io::SeekFrom::Current(0))? as u32;
-                if cur_posi >= file_sz {
+        loop {
+            let cur_posi = reader.stream_position()? as u32;
+            if cur_posi >= file_sz {
+                break;
+            }
+            let (record, value_sz) = match Record::from_reader(&mut reader, version) {
+                Ok(read) => read,
+                // only the active file can have been torn by a crash, immutable files were
+                // complete when we rotated away from them
+                Err(e)
+                    if is_active && is_torn_tail(&e, reader.stream_position()? as u32, file_sz) =>
+                {
+                    // a good record behind the damage means it wasn't torn by a crash, the
+                    // damage is in the middle and truncating would throw good records away
+                    let mut rest = vec![0; (file_sz - cur_posi) as usize];
+                    file.read_exact_at(&mut rest, cur_posi as u64)?;
+                    if let Some(next) = record::next_good_record(&rest[1..], version) {
+                        let reason = format!(
+                            "Damaged record followed by a good one at {}: {e}",
+                            cur_posi + 1 + next as u32
+                        );
+                        return Err(Error::corrupt(reason).at(&path, cur_posi));
+                    }
+                    if options.read_only {
+                        warn!("Ignoring torn record at {path}:{cur_posi}: {e}");
+                        end = cur_posi;
+                        break;
+                    }
+                    file.set_len(cur_posi as u64)?;
+                    let discarded_bytes = file_sz - cur_posi;
+                    warn!(
+                        "Truncated torn record at {path}:{cur_posi}, discarded {discarded_bytes} bytes: {e}"
+                    );
+                    recovery = Some(Recovery {
+                        file_id: path.clone(),
+                        offset: cur_posi,
+                        discarded_bytes,
+                    });
                     break;
                 }
-                let record = Record::from_reader(&mut reader)?;
-                if record.value == TOMBSTONE {
-                    continue;
-                };
-                key_dir.insert(
-                    record.key,
-                    KeyDirValue {
-                        // ts,
-                        value_sz: 1,
-                        file_id: active_file_id.clone().unwrap(),
-                        value_posi: cur_posi,
-                    },
+                Err(e) => return Err(e.at(&path, cur_posi)),
+            };
+            let record_sz = reader.stream_position()? as u32 - cur_posi;
+
+            // records of a batch only count once its commit marker is read
+            let is_begin = record.is_batch_begin();
+            let mut dead =
+                batches.push(&path, record, cur_posi, record_sz, |record, at, size| {
+                    // batches are only written in the current format, where values are
+                    // stored as they are
+                    let value_sz = match at == cur_posi {
+                        true => value_sz,
+                        false => record.value.len() as u32,
+                    };
+                    loader.load_record(&path, record, at, size, value_sz);
+                    return Ok(());
+                })?;
+            if is_begin || batches.pending().is_none() {
+                dead += std::mem::take(&mut begin_sz);
+            }
+            if is_begin {
+                dead -= record_sz;
+                begin_sz = record_sz;
+            }
+            loader.add_dead(&path, dead);
+        }
+
+        // a batch cut off by a crash, it goes the same way as a torn record
+        if let Some(batch) = batches.take_pending() {
+            let offset = batch.begin_posi;
+            if is_writable {
+                file.set_len(offset as u64)?;
+                let discarded_bytes = file_sz - offset;
+                warn!(
+                    "Truncated uncommitted batch at {path}:{offset}, discarded {discarded_bytes} bytes"
                 );
+                recovery = Some(Recovery {
+                    file_id: path.clone(),
+                    offset,
+                    discarded_bytes,
+                });
+            } else if is_active {
+                // the writer can still be appending it, it's read again on refresh
+                end = offset;
+            } else {
+                warn!("Ignoring uncommitted batch at {path}:{offset}");
+                loader.add_dead(&path, begin_sz + batch.size());
+            }
+        }
+
+        files.push(FilWithId::new(dir, path, file, version));
+    }
+
+    let Loader {
+        key_dir,
+        dead,
+        deleted,
+        ..
+    } = loader;
+    for f in files.iter() {
+        let dead_bytes = dead.get(&f.id).copied().unwrap_or(0);
+        f.dead_bytes.store(dead_bytes, Ordering::Relaxed);
+    }
+    return Ok(Loaded {
+        files,
+        key_dir,
+        deleted,
+        recovery,
+        end,
+    });
+}
+
+/// Wraps freshly loaded files for sharing, mapping the immutable ones when
+/// `Options::mmap` asks for it
+fn share_files(files: Vec<FilWithId>, options: &Options) -> Vec<Arc<FilWithId>> {
+    let files: Vec<_> = files.into_iter().map(Arc::new).collect();
+    if options.mmap {
+        // the last file is the active one, or can still be appended to by a
+        // writer when the store is opened read only
+        for f in files.iter().rev().skip(1) {
+            f.map();
+        }
+    }
+    return files;
+}
+
+impl Store {
+    /// Opens the store in the directory at `path`, loading the key dir from its
+    /// files. Fails with `Error::Locked` while another process has it open for
+    /// writing, unless `options` opens it read only.
+    pub fn open(path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        check_options(&options)?;
+        let dir = path.as_ref().to_path_buf();
+        if !dir.is_dir() {
+            if !options.create_if_missing || options.read_only {
+                let msg = format!("Store directory {} does not exist", dir.display());
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
             }
+            fs::create_dir_all(&dir)?;
+        }
+
+        let lock = match options.read_only {
+            true => None,
+            false => Some(lock_dir(&dir)?),
+        };
 
-            files.push(FilWithId { id: path, file });
+        if !options.read_only {
+            // outputs of a merge that was interrupted before it could swap them in
+            for e in fs::read_dir(&dir)? {
+                let path = e?.path();
+                if path.extension().is_some_and(|ext| ext == "merge") {
+                    fs::remove_file(path)?;
+                }
+            }
         }
 
-        println!("Active file {:?}", active_file_id);
+        let (listing, loaded) = load_consistent(&dir, &options)?;
+        let newest = listing
+            .files
+            .last()
+            .map(|(id, _)| parse_id(id))
+            .transpose()?;
+        let mut ids = FileIds::open(&dir, newest, !options.read_only)?;
+        let Loaded {
+            mut files,
+            key_dir,
+            recovery,
+            ..
+        } = loaded;
+
+        // records are only ever appended in the current format, so an active file left
+        // behind by an older version is kept as immutable and a new one is started.
+        // A replica only gets files from its primary until it is promoted.
+        let creates_active = !options.read_only && !options.replica;
+        if creates_active && files.last().is_none_or(|f| f.version != VERSION) {
+            let id = ids.new_id()?;
+            let active_file = open_file(&dir, &id)?;
 
-        if active_file_id.is_none() {
-            let id = get_sortable_id();
-            active_file_id = Some(id.clone());
+            files.push(FilWithId::new(&dir, id, active_file, VERSION));
+        }
+        let cur_posi = match files.last_mut() {
+            Some(f) => f.file.seek(SeekFrom::End(0))? as u32,
+            None => 0,
+        };
 
-            let active_file = open_file(&id)?;
+        let mut syncer = None;
+        if let (SyncPolicy::Interval(interval), Some(f)) = (options.sync, files.last()) {
+            if !options.read_only {
+                syncer = Some(Syncer::spawn(f.file.try_clone()?, interval));
+            }
+        }
+
+        let files = share_files(files, &options);
+        let active = match options.read_only {
+            true => None,
+            false => files.last().cloned(),
+        };
 
-            files.push(FilWithId {
-                id,
-                file: active_file,
+        let replica = options.replica;
+        let inner = Arc::new_cyclic(|weak: &Weak<Inner>| {
+            let compactor = match options.read_only {
+                true => None,
+                false => Some(spawn_compactor(weak.clone(), &options)),
+            };
+            let refresher = match (options.read_only, options.refresh_interval) {
+                (true, Some(interval)) => Some(spawn_refresher(weak.clone(), interval)),
+                _ => None,
+            };
+            return Inner {
+                dir,
+                options,
+                key_dir: RwLock::new(key_dir),
+                files: RwLock::new(files),
+                writer: Mutex::new(Writer {
+                    active,
+                    cur_posi,
+                    syncer,
+                    ids,
+                }),
+                recovery,
+                merging: Mutex::new(vec![]),
+                compactor,
+                listing: Mutex::new(listing),
+                subscribers: Mutex::new(vec![]),
+                appends: Mutex::new(vec![]),
+                replica: AtomicBool::new(replica),
+                _refresher: refresher,
+                _lock: lock,
+            };
+        });
+        return Ok(Self { inner });
+    }
+
+    /// Picks up what a writer in another process did since this read only store
+    /// was opened or last refreshed. Records appended since are loaded on top
+    /// of the key dir, after a merge the files are loaded anew. Writable stores
+    /// are always up to date.
+    pub fn refresh(&self) -> Result<()> {
+        let inner = &self.inner;
+        if !inner.options.read_only {
+            return Ok(());
+        }
+        let mut listing = inner.listing.lock().unwrap();
+        let files = list_data_files(&inner.dir)?;
+        if listing.files == files {
+            return Ok(());
+        }
+        if listing.is_extended_by(&files) {
+            match self.load_appended(&listing, &files) {
+                Ok(end) => {
+                    *listing = Listing { files, end };
+                    return Ok(());
+                }
+                // merged away meanwhile
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let (new_listing, loaded) = load_consistent(&inner.dir, &inner.options)?;
+        let files = share_files(loaded.files, &inner.options);
+        let mut key_dir = inner.key_dir.write().unwrap();
+        *inner.files.write().unwrap() = files;
+        *key_dir = loaded.key_dir;
+        *listing = new_listing;
+        return Ok(());
+    }
+
+    /// Loads what was appended to the files in `listing` to get to `files`, the
+    /// rest of the last one and every newer one, and returns where it stopped
+    fn load_appended(&self, listing: &Listing, files: &[(String, u64)]) -> Result<u32> {
+        let inner = &self.inner;
+        let appended = &files[listing.files.len() - 1..];
+        let loader = Loader::new(&inner.options, true);
+        let loaded = load(&inner.dir, appended, listing.end, loader, &inner.options)?;
+
+        let mut new_files = loaded.files.into_iter();
+        let grown = new_files.next().expect("the last file is loaded again");
+        let new_files = share_files(new_files.collect(), &inner.options);
+
+        let mut key_dir = inner.key_dir.write().unwrap();
+        let mut all_files = inner.files.write().unwrap();
+        let last = all_files.last().expect("the last file is loaded");
+        let grown_dead = grown.dead_bytes.load(Ordering::Relaxed);
+        last.dead_bytes.fetch_add(grown_dead, Ordering::Relaxed);
+        if inner.options.mmap && !new_files.is_empty() {
+            last.map();
+        }
+        all_files.extend(new_files);
+
+        for key in loaded.deleted.unwrap_or_default() {
+            if let Some(old) = key_dir.remove(&key) {
+                mark_dead(&all_files, &old);
+            }
+        }
+        for (key, val) in loaded.key_dir.into_entries() {
+            if let Some(old) = key_dir.insert(key, val) {
+                mark_dead(&all_files, &old);
+            }
+        }
+        return Ok(loaded.end);
+    }
+
+    /// Creates a store at `path` from a snapshot taken by `Store::snapshot` and
+    /// opens it. `path` must not exist yet or be empty. The files are linked
+    /// like when taking the snapshot, which stays as it is.
+    pub fn restore(
+        snapshot: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+        options: Options,
+    ) -> Result<Self> {
+        check_options(&options)?;
+        let (snapshot, path) = (snapshot.as_ref(), path.as_ref());
+        let manifest = Manifest::read(snapshot)?;
+        snapshot::create_empty_dir(path)?;
+        for (id, _) in manifest.files.iter() {
+            snapshot::link_or_copy(&snapshot.join(id), &path.join(id))?;
+            let hint = hint_path(snapshot, id);
+            if hint.exists() {
+                snapshot::link_or_copy(&hint, &hint_path(path, id))?;
+            }
+        }
+        // appends go to a new active file, never into one shared with the snapshot
+        let newest = manifest
+            .files
+            .last()
+            .map(|(id, _)| parse_id(id))
+            .transpose()?;
+        open_file(path, &FileIds::open(path, newest, true)?.new_id()?)?;
+        return Self::open(path, options);
+    }
+
+    /// Set when opening the store had to discard a torn tail of the active file
+    pub fn recovery(&self) -> Option<&Recovery> {
+        return self.inner.recovery.as_ref();
+    }
+
+    /// Sets `key` to `value`, see `put_bytes` for keys and values that aren't text
+    pub fn put(&self, key: String, value: String) -> Result<()> {
+        return self.put_bytes(key.as_bytes(), value.as_bytes());
+    }
+
+    /// The value of `key`, `None` if it was never put, deleted or expired
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        return match self.get_bytes(key.as_bytes())? {
+            Some(value) => match String::from_utf8(value) {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(Error::invalid("Value is not UTF-8, read it with get_bytes")),
+            },
+            None => Ok(None),
+        };
+    }
+
+    /// Removes `key`, deleting a key that isn't there is not an error
+    pub fn delete(&self, key: String) -> Result<()> {
+        return self.delete_bytes(key.as_bytes());
+    }
+
+    /// Locks the writer, failing for read only stores and replicas
+    fn writer(&self) -> Result<MutexGuard<'_, Writer>> {
+        if self.inner.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.is_replica() {
+            return Err(Error::Replica);
+        }
+        return Ok(self.inner.writer.lock().unwrap());
+    }
+
+    /// Whether the store follows a primary, see `Replica`
+    pub fn is_replica(&self) -> bool {
+        return self.inner.replica.load(Ordering::Acquire);
+    }
+
+    /// Appends a serialized record to the active file and returns the file id and
+    /// position it was written at. Rotates the active file once it is full.
+    fn append(&self, writer: &mut Writer, serialized: &[u8]) -> Result<(String, u32)> {
+        let active = writer.active()?.clone();
+        let end = writer.cur_posi as u64 + serialized.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(Error::ValueTooLarge {
+                size: serialized.len(),
             });
         }
-        let files_length = files.len();
+        self.write_active(writer, serialized)?;
 
-        let cur_posi = files
-            .get_mut(files_length - 1)
-            .unwrap()
-            .file
-            .seek(std::io::SeekFrom::End(0))? as u32;
+        let written_at = (active.id.clone(), writer.cur_posi);
+        self.publish_append(&active.id, writer.cur_posi, serialized);
+        writer.cur_posi += serialized.len() as u32;
 
-        let active_file_id = active_file_id.unwrap();
-        println!("Key Dir: {:?}", key_dir);
-        return Ok(Self {
-            key_dir,
-            files,
-            cur_posi,
-            active_file_id,
+        if writer.cur_posi >= self.inner.options.max_file_sz {
+            let id = writer.ids.new_id()?;
+            self.rotate(writer, id)?;
+        }
+
+        return Ok(written_at);
+    }
+
+    /// Writes to the end of the active file and syncs by the sync policy
+    fn write_active(&self, writer: &Writer, bytes: &[u8]) -> Result<()> {
+        let active = writer.active()?;
+        (&active.file).write_all(bytes)?;
+        match (self.inner.options.sync, &writer.syncer) {
+            (SyncPolicy::Always, _) => active.file.sync_data()?,
+            (SyncPolicy::Interval(_), Some(syncer)) => syncer.mark_dirty(),
+            _ => {}
+        }
+        return Ok(());
+    }
+
+    /// Closes the active file and continues in a new one named `id`. The old one
+    /// is synced first whatever the sync policy, once it isn't the newest file
+    /// a torn tail in it can't be recovered anymore. Replicas get the header, so
+    /// they move on to the new file even when nothing is put for a while.
+    fn rotate(&self, writer: &mut Writer, id: String) -> Result<()> {
+        if let Some(old) = &writer.active {
+            old.file.sync_data()?;
+        }
+        let file = open_file(&self.inner.dir, &id)?;
+        self.publish_append(&id, 0, &record::header());
+        let active = FilWithId::new(&self.inner.dir, id, file, VERSION);
+        return self.install_active(writer, active, HEADER_SZ);
+    }
+
+    /// Makes `active` the file appends go to from `cur_posi` on
+    fn install_active(&self, writer: &mut Writer, active: FilWithId, cur_posi: u32) -> Result<()> {
+        match (&writer.syncer, self.inner.options.sync) {
+            (Some(syncer), _) => syncer.set_file(active.file.try_clone()?)?,
+            // a replica starting out empty has had no file to sync yet
+            (None, SyncPolicy::Interval(interval)) => {
+                writer.syncer = Some(Syncer::spawn(active.file.try_clone()?, interval));
+            }
+            (None, _) => {}
+        }
+
+        let active = Arc::new(active);
+        self.inner.files.write().unwrap().push(active.clone());
+        if let Some(old) = writer.active.replace(active) {
+            if self.inner.options.mmap {
+                old.map();
+            }
+        }
+        writer.cur_posi = cur_posi;
+        return Ok(());
+    }
+
+    /// Writes a consistent copy of the store to `dest`, which must not exist yet
+    /// or be empty. The active file is rotated first, the snapshot then holds
+    /// exactly what was written before that. Data files are never modified once
+    /// rotated away from, so they are hard linked rather than copied when `dest`
+    /// is on the same file system.
+    pub fn snapshot(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        snapshot::create_empty_dir(dest)?;
+
+        let (files, created_at) = {
+            let mut writer = self.writer()?;
+            if writer.cur_posi > HEADER_SZ {
+                let id = writer.ids.new_id()?;
+                self.rotate(&mut writer, id)?;
+            }
+            let active_id = writer.active()?.id.clone();
+            let files = self.inner.files.read().unwrap().clone();
+            let files: Vec<_> = files.into_iter().filter(|f| f.id != active_id).collect();
+            (files, record::now_secs())
+        };
+
+        // holding on to the files keeps a merge from deleting them meanwhile
+        let mut manifest = Manifest {
+            created_at,
+            files: vec![],
+        };
+        for f in files.iter() {
+            f.file.sync_all()?;
+            snapshot::link_or_copy(&f.path, &dest.join(&f.id))?;
+            let hint = hint_path(&self.inner.dir, &f.id);
+            if hint.exists() {
+                snapshot::link_or_copy(&hint, &hint_path(dest, &f.id))?;
+            }
+            manifest
+                .files
+                .push((f.id.clone(), f.file.metadata()?.size()));
+        }
+        manifest.write(dest)?;
+        return Ok(());
+    }
+
+    /// Live and dead bytes of every data file
+    pub fn stats(&self) -> Result<Stats> {
+        let keys = self.inner.key_dir.read().unwrap().len();
+        let files = self.inner.files.read().unwrap().clone();
+        return Ok(Stats {
+            keys,
+            files: files.iter().map(|f| f.stats()).collect::<Result<_>>()?,
         });
     }
 
-    pub fn put(&mut self, key: String, value: String) -> Result<()> {
-        let value = escape_tombstone(value);
-        let record = Record { key, value };
+    /// Forces everything written so far to disk, regardless of the sync policy
+    pub fn sync(&self) -> Result<()> {
+        if self.inner.options.read_only {
+            return Ok(());
+        }
+        let writer = self.inner.writer.lock().unwrap();
+        if let Some(syncer) = &writer.syncer {
+            return syncer.sync();
+        }
+        if let Some(active) = &writer.active {
+            active.file.sync_data()?;
+        }
+        return Ok(());
+    }
+
+    /// Puts a key that reads as deleted once `ttl` passed. Expired keys are
+    /// dropped by the next merge or when the store is opened.
+    pub fn put_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        return self.put_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl);
+    }
+
+    pub fn put_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        return self.put_record(Record::new(key.to_vec(), value.to_vec()));
+    }
+
+    pub fn put_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        return self.put_record(Record::expiring(key.to_vec(), value.to_vec(), ttl));
+    }
+
+    fn put_record(&self, mut record: Record) -> Result<()> {
+        record.check_size()?;
+        if let Some(compression) = &self.inner.options.compression {
+            record.compress(compression)?;
+        }
+        let mut writer = self.writer()?;
+
         let serialized = record.serialize();
-        // println!("Active file id:{}", self.active_file_id);
-        // println!("Files:{:?}", self.files);
-        let file = self
-            .files
-            .iter_mut()
-            .find(|f| f.id == self.active_file_id)
-            .unwrap();
+        let (file_id, record_posi) = self.append(&mut writer, &serialized)?;
 
-        println!("File: {:?}", file);
-        let written = file.file.write(&serialized)? as u32;
-        self.key_dir.insert(
+        let mut key_dir = self.inner.key_dir.write().unwrap();
+        self.publish(&record, &file_id, record_posi, serialized.len() as u32);
+        let old = key_dir.insert(
             record.key,
             KeyDirValue {
-                file_id: self.active_file_id.clone(),
-                // value_sz: written,
+                file_id,
                 value_sz: record.value.len() as u32,
-                value_posi: self.cur_posi.clone(),
+                record_posi,
+                record_sz: serialized.len() as u32,
+                expires_at: record.expires_at,
             },
         );
+        if let Some(old) = old {
+            mark_dead(&self.inner.files.read().unwrap(), &old);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        return Ok(self.get_value(key)?.map(Value::into_vec));
+    }
+
+    /// Like `get_bytes`, but a value in a memory mapped file is returned without
+    /// copying it, see `Options::mmap`
+    pub fn get_value(&self, key: &[u8]) -> Result<Option<Value>> {
+        let (val, file) = {
+            let key_dir = self.inner.key_dir.read().unwrap();
+            let Some(val) = key_dir.get(key).cloned() else {
+                return Ok(None);
+            };
+            if val.is_expired(record::now_secs()) {
+                return Ok(None);
+            }
+            let files = self.inner.files.read().unwrap();
+            let Some(file) = files.iter().find(|f| f.id == val.file_id).cloned() else {
+                let missing = Error::corrupt("Data file of the key is missing");
+                return Err(missing.at(&val.file_id, val.record_posi));
+            };
+            (val, file)
+        };
+
+        // the locks are released, reads of the file itself happen concurrently
+        let (start, end) = (
+            val.record_posi as usize,
+            (val.record_posi + val.record_sz) as usize,
+        );
+        if let Some(mmap) = file.mmap.get() {
+            let bytes = mmap
+                .get(start..end)
+                .ok_or_else(|| Error::corrupt("Record is past the end of the file"))
+                .map_err(|e| e.at(&file.id, val.record_posi))?;
+            let (flags, value_start) = record::check_record(bytes, val.value_sz, file.version)
+                .map_err(|e| e.at(&file.id, val.record_posi))?;
+            if record::is_stored_as_is(file.version, flags) {
+                return Ok(Some(Value::mapped(mmap.clone(), start + value_start..end)));
+            }
+            let value = bytes[value_start..].to_vec();
+            let value = record::decode_value(file.version, flags, value)?;
+            return Ok(Some(Value::owned(value)));
+        }
 
-        self.cur_posi += written;
+        let mut buf = vec![0; val.record_sz as usize];
+        file.file.read_exact_at(&mut buf, start as u64)?;
+        let value = record::checked_value(buf, val.value_sz, file.version)?;
+        return Ok(Some(Value::owned(value)));
+    }
 
-        if self.cur_posi >= MAX_FILE_SZ {
-            let id = get_sortable_id();
-            let file = open_file(&id)?;
+    /// Every key in order, expired ones left out
+    pub fn keys(&self) -> Keys {
+        return scan::keys(self.clone());
+    }
 
-            //TODO:  consider using lifetimes to avoid clones
-            self.files.push(FilWithId {
-                id: id.clone(),
-                file,
+    /// Keys starting with `prefix` and their values, in key order
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan {
+        let upper = match scan::prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        return scan::range(self.clone(), Bound::Included(prefix.to_vec()), upper);
+    }
+
+    /// Keys within `range` and their values, in key order
+    pub fn range<K, R>(&self, range: R) -> Scan
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let to_vec = |bound: Bound<&K>| bound.map(|k| k.as_ref().to_vec());
+        return scan::range(
+            self.clone(),
+            to_vec(range.start_bound()),
+            to_vec(range.end_bound()),
+        );
+    }
+
+    /// One page of keys for the scan iterators
+    pub(crate) fn keys_in(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        limit: usize,
+    ) -> (Vec<Vec<u8>>, bool) {
+        let key_dir = self.inner.key_dir.read().unwrap();
+        return key_dir.keys_in(lower, upper, record::now_secs(), limit);
+    }
+
+    pub fn delete_bytes(&self, key: &[u8]) -> Result<()> {
+        let mut writer = self.writer()?;
+
+        if !self.inner.key_dir.read().unwrap().contains_key(key) {
+            return Ok(());
+        }
+
+        let record = Record::tombstone(key.to_vec());
+        let serialized = record.serialize();
+        let (file_id, record_posi) = self.append(&mut writer, &serialized)?;
+
+        let mut key_dir = self.inner.key_dir.write().unwrap();
+        self.publish(&record, &file_id, record_posi, serialized.len() as u32);
+        if let Some(old) = key_dir.remove(&record.key) {
+            mark_dead(&self.inner.files.read().unwrap(), &old);
+        }
+        return Ok(());
+    }
+
+    /// Applies every put and delete of `batch` in order, or none of them if the
+    /// process dies while it is being written
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut records = batch.into_records();
+        for record in records.iter() {
+            record.check_size()?;
+        }
+        if let Some(compression) = &self.inner.options.compression {
+            for record in records.iter_mut() {
+                record.compress(compression)?;
+            }
+        }
+        let mut writer = self.writer()?;
+
+        let mut buf = batch::begin_marker(records.len()).serialize();
+        let mut markers_sz = buf.len() as u32;
+        // offset of each record within the batch and its size
+        let mut offsets = vec![];
+        let mut crc = crc32fast::Hasher::new();
+        for record in records.iter() {
+            let serialized = record.serialize();
+            offsets.push((buf.len() as u32, serialized.len() as u32));
+            crc.update(&serialized);
+            buf.extend_from_slice(&serialized);
+        }
+        let commit = batch::commit_marker(crc.finalize()).serialize();
+        markers_sz += commit.len() as u32;
+        buf.extend_from_slice(&commit);
+
+        // a single write, so the batch can't end up split across two files
+        let (file_id, batch_posi) = self.append(&mut writer, &buf)?;
+
+        let mut key_dir = self.inner.key_dir.write().unwrap();
+        let files = self.inner.files.read().unwrap();
+        add_dead(&files, &file_id, markers_sz);
+        for (record, (offset, record_sz)) in records.into_iter().zip(offsets) {
+            self.publish(&record, &file_id, batch_posi + offset, record_sz);
+            let (posi, value_sz) = (batch_posi + offset, record.value.len() as u32);
+            index_record(
+                &mut key_dir,
+                &files,
+                &file_id,
+                record,
+                posi,
+                record_sz,
+                value_sz,
+            );
+        }
+        return Ok(());
+    }
+
+    /// The data files from `from` on as far as they are written, or all of them
+    /// with `None`. The file of `from` starts at its offset, the others at 0.
+    /// Fails when the file of `from` was merged away or is shorter than `from`.
+    fn replay_ranges(
+        &self,
+        writer: &Writer,
+        files: &[Arc<FilWithId>],
+        from: Option<&Position>,
+    ) -> Result<Vec<ReplayRange>> {
+        let active = writer.active()?;
+        let end_of = |f: &FilWithId| -> Result<u32> {
+            return match f.id == active.id {
+                true => Ok(writer.cur_posi),
+                false => Ok(f.file.metadata()?.size() as u32),
+            };
+        };
+        if let Some(from) = from {
+            let Some(f) = files.iter().find(|f| f.id == from.file_id) else {
+                return Err(Error::PositionGone(from.clone()));
+            };
+            if from.offset > end_of(f)? {
+                return Err(Error::PositionGone(from.clone()));
+            }
+        }
+
+        let mut replay = vec![];
+        for f in files.iter() {
+            let start = match from {
+                Some(from) if file_id_num(&f.id) < file_id_num(&from.file_id) => continue,
+                Some(from) if f.id == from.file_id => from.offset,
+                _ => 0,
+            };
+            replay.push(ReplayRange {
+                file_id: f.id.clone(),
+                version: f.version,
+                file: fs::File::open(&f.path)?,
+                start,
+                end: end_of(f)?,
             });
-            self.active_file_id = id;
-            self.cur_posi = 0
         }
+        return Ok(replay);
+    }
 
-        Ok(())
+    /// Follows every put and delete from now on
+    pub fn subscribe(&self) -> Result<Subscription> {
+        let _writer = self.writer()?;
+        let (sender, receiver) = mpsc::channel();
+        self.inner.subscribers.lock().unwrap().push(sender);
+        return Ok(Subscription::new(vec![], receiver));
     }
 
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        let val = self.key_dir.get(&key);
+    /// Replays the records in the data files from `from` on, or from the oldest
+    /// file with `None`, and then follows every put and delete like `subscribe`.
+    /// Fails when the file of `from` was merged away since, or is shorter than
+    /// `from`, the changes in it can't be told apart anymore and the consumer
+    /// has to start over.
+    ///
+    /// When a merge left the file of `from` alone, the files it wrote still
+    /// come after it, and they can hold copies of records from before `from`.
+    /// Those are replayed again, out of order and with their original `ts`, so
+    /// a consumer resuming from a position has to handle changes it has seen.
+    pub fn subscribe_from(&self, from: Option<&Position>) -> Result<Subscription> {
+        // nothing is written while the files are picked, so the replay ends right
+        // where the changes sent to the subscription begin
+        let writer = self.writer()?;
+        let files = self.inner.files.read().unwrap().clone();
+        let replay = self.replay_ranges(&writer, &files, from)?;
 
-        println!("Key: {}, val: {:?}", key, val);
-        if let Some(val) = val {
-            // taking integers as 5 bytes because the maximum size of u32 in google protobuf
-            // encoding is 5 bytes
-            let n = val.value_sz + key.len() as u32 + 5 /* crc */ + 5 /* ts */+ 5 /* keysz */+ 5 /* valuesz */;
-            // let n = val.value_sz;
-            let mut buf: Vec<u8> = vec![0; n as usize];
-            let FilWithId { file, .. } = self.files.iter().find(|f| f.id == val.file_id).unwrap();
-            file.read_at(&mut buf, val.value_posi as u64)?;
+        let (sender, receiver) = mpsc::channel();
+        self.inner.subscribers.lock().unwrap().push(sender);
+        return Ok(Subscription::new(replay, receiver));
+    }
 
-            let (record, _read_bytes) = Record::from_bytes(buf)?;
-            let value = unescape_tombstone(record.value);
-            return Ok(Some(value));
+    /// Sends a record just written to the subscriptions, dropping the ones that
+    /// went away. Called with the writer and the key dir locked, so changes go
+    /// out in the order they were written and a subscriber reading the key gets
+    /// at least this value.
+    fn publish(&self, record: &Record, file_id: &str, offset: u32, size: u32) {
+        let mut subscribers = self.inner.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        match Change::from_record(record, file_id, offset, size) {
+            Ok(change) => subscribers.retain(|s| s.send(change.clone()).is_ok()),
+            Err(e) => warn!("Failed to publish change at {file_id}:{offset}: {e}"),
         }
-        return Ok(None);
     }
 
-    pub fn delete(&mut self, key: String) -> Result<()> {
-        let val = self.key_dir.get(&key);
+    /// Sends bytes just appended at `file_id:offset` to the replicas, dropping the
+    /// ones that went away. Called with the writer locked, so they get them in
+    /// the order they were written.
+    fn publish_append(&self, file_id: &str, offset: u32, bytes: &[u8]) {
+        let mut appends = self.inner.appends.lock().unwrap();
+        if appends.is_empty() {
+            return;
+        }
+        let append = Replicated::Append(Append {
+            file_id: file_id.to_string(),
+            offset,
+            bytes: bytes.into(),
+        });
+        appends.retain(|s| s.send(append.clone()).is_ok());
+    }
 
-        if val.is_none() {
-            return Ok(());
+    /// Sends a merge that wrote `merged` in place of `replaced` to the replicas.
+    /// Called with the files locked, so a replica that starts following
+    /// meanwhile either gets the files from before the merge and then this, or
+    /// the files from after it.
+    fn publish_merge(&self, merged: &[Arc<FilWithId>], replaced: &[Arc<FilWithId>]) {
+        let mut appends = self.inner.appends.lock().unwrap();
+        if appends.is_empty() {
+            return;
         }
+        let mut outputs = vec![];
+        for f in merged {
+            match f.file.try_clone() {
+                Ok(file) => outputs.push((f.id.clone(), Arc::new(file))),
+                Err(e) => {
+                    // they would miss the merge, they reconnect instead
+                    error!("Failed to send merged file {} to replicas: {e}", f.id);
+                    appends.clear();
+                    return;
+                }
+            }
+        }
+        let merge = Replicated::Merge {
+            outputs,
+            replaced: replaced.iter().map(|f| f.id.clone()).collect(),
+        };
+        appends.retain(|s| s.send(merge.clone()).is_ok());
+    }
 
-        let record = Record {
-            key,
-            value: TOMBSTONE.to_string(),
+    pub(crate) fn downgrade(&self) -> WeakStore {
+        return WeakStore {
+            inner: Arc::downgrade(&self.inner),
         };
+    }
 
-        let written = self
-            .files
-            .iter_mut()
-            .find(|f| f.id == self.active_file_id)
-            .unwrap()
-            .file
-            .write(&record.serialize())?;
-
-        self.key_dir.remove(&record.key);
-        self.cur_posi += written as u32;
+    /// What a replica at `from` is missing, or a new one with `None`: the rest
+    /// of the data files as they are now, then every append from here on. The
+    /// files are sent whole, headers included. Fails with `PositionGone` when
+    /// the file of `from` was merged away or is shorter than `from`.
+    pub(crate) fn stream_appends(
+        &self,
+        from: Option<&Position>,
+    ) -> Result<(Vec<ReplayRange>, mpsc::Receiver<Replicated>)> {
+        // nothing is written while the files are picked, so the ranges end right
+        // where the appends sent to the receiver begin. Holding the files keeps
+        // merges from swapping theirs in until the receiver gets them.
+        let writer = self.writer()?;
+        let files = self.inner.files.read().unwrap();
+        let replay = self.replay_ranges(&writer, &files, from)?;
+
+        let (sender, receiver) = mpsc::channel();
+        self.inner.appends.lock().unwrap().push(sender);
+        return Ok((replay, receiver));
+    }
+
+    /// The end of the replica's last file, where replication continues from
+    pub(crate) fn replica_position(&self) -> Option<Position> {
+        let writer = self.inner.writer.lock().unwrap();
+        return writer.active.as_ref().map(|f| Position {
+            file_id: f.id.clone(),
+            offset: writer.cur_posi,
+        });
+    }
+
+    /// Writes bytes the primary appended to the same place in the replica's
+    /// files and indexes the records they complete. A new file starts with its
+    /// header, which the primary sends when it rotates into it.
+    pub(crate) fn apply_append(&self, tail: &mut Tail, append: &Append) -> Result<()> {
+        let inner = &self.inner;
+        let mut writer = inner.writer.lock().unwrap();
+        if writer
+            .active
+            .as_ref()
+            .is_none_or(|f| f.id != append.file_id)
+        {
+            if let Some(active) = &writer.active {
+                if file_id_num(&append.file_id) <= file_id_num(&active.id) {
+                    let msg = format!("Got file {} after {}", append.file_id, active.id);
+                    return Err(Error::invalid(msg));
+                }
+                if !tail.is_empty() {
+                    warn!("Discarding incomplete records at the end of {}", active.id);
+                }
+                // like when rotating, the old file must not be torn once a newer one exists
+                active.file.sync_data()?;
+            }
+            let id = parse_id(&append.file_id)?;
+            writer.ids.advance_past(id)?;
+            let (file, version) = match append.offset {
+                0 => {
+                    let file = OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create_new(true)
+                        .open(inner.dir.join(&append.file_id))?;
+                    (
+                        file,
+                        record::parse_version(&append.bytes)
+                            .map_err(|e| e.at(&append.file_id, 0))?
+                            .unwrap_or(VERSION),
+                    )
+                }
+                offset => {
+                    return Err(Error::invalid(format!(
+                        "Got file {} from {offset} on, without its start",
+                        append.file_id
+                    )))
+                }
+            };
+            let active = FilWithId::new(&inner.dir, append.file_id.clone(), file, version);
+            self.install_active(&mut writer, active, append.offset)?;
+            *tail = Tail::at(append.offset);
+        }
+        if append.offset != writer.cur_posi {
+            return Err(Error::invalid(format!(
+                "Got {}:{} while at {}",
+                append.file_id, append.offset, writer.cur_posi
+            )));
+        }
+        self.write_active(&writer, &append.bytes)?;
+        writer.cur_posi += append.bytes.len() as u32;
+
+        let active = writer.active()?.clone();
+        tail.bytes.extend_from_slice(&append.bytes);
+        let mut used = record::records_start(active.version).saturating_sub(tail.posi) as usize;
+        if used > tail.bytes.len() {
+            return Ok(());
+        }
+
+        let mut key_dir = inner.key_dir.write().unwrap();
+        let files = inner.files.read().unwrap();
+        while used < tail.bytes.len() {
+            let posi = tail.posi + used as u32;
+            let mut rest = &tail.bytes[used..];
+            let (record, value_sz) = match Record::from_reader(&mut rest, active.version) {
+                Ok(read) => read,
+                // the rest of the record comes with the next append
+                Err(e) if e.is_io(io::ErrorKind::UnexpectedEof) => break,
+                Err(e) => return Err(e.at(&active.id, posi)),
+            };
+            let record_sz = (tail.bytes.len() - used - rest.len()) as u32;
+            used += record_sz as usize;
+
+            // like when loading, records of a batch only count once it commits
+            let dead =
+                tail.batches
+                    .push(&active.id, record, posi, record_sz, |record, at, size| {
+                        let value_sz = match at == posi {
+                            true => value_sz,
+                            false => record.value.len() as u32,
+                        };
+                        index_record(&mut key_dir, &files, &active.id, record, at, size, value_sz);
+                        return Ok(());
+                    })?;
+            add_dead(&files, &active.id, dead);
+        }
+        tail.bytes.drain(..used);
+        tail.posi += used as u32;
         return Ok(());
     }
 
-    pub fn merge_and_compact(&mut self) -> Result<()> {
-        let id = (self.files[0].id.parse::<u32>()? - 1).to_string();
-        let mut new_file = open_file(&id)?;
+    /// Writes bytes of a file a merge of the primary wrote, named `<id>.merge`
+    /// like it is on the primary until the merge is swapped in
+    pub(crate) fn write_merged(&self, append: &Append) -> Result<()> {
+        let path = self.inner.dir.join(format!("{}.merge", append.file_id));
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(append.offset == 0)
+            .open(path)?;
+        file.write_all_at(&append.bytes, append.offset as u64)?;
+        return Ok(());
+    }
 
-        let mut writer_posi = 0;
+    /// Swaps in the files a merge of the primary wrote, all received by now, in
+    /// place of the `replaced` ones. Every key still in a file older than the
+    /// merged ones was live when the primary merged, so it moves to its copy.
+    /// The keys left in the replaced files are those the merge dropped.
+    pub(crate) fn apply_merge(&self, outputs: &[String], replaced: &[String]) -> Result<()> {
+        let inner = &self.inner;
+        let mut retired = inner.merging.lock().unwrap();
 
-        let mut processed_ids = vec![];
-        for f in self.files.iter_mut() {
-            // active file won't be touched
-            if f.id == self.active_file_id {
-                continue;
+        let mut loader = Loader::new(&inner.options, false);
+        let mut merged = vec![];
+        for id in outputs {
+            let path = inner.dir.join(id);
+            let merge_path = inner.dir.join(format!("{id}.merge"));
+            if inner.options.sync != SyncPolicy::Os {
+                fs::File::open(&merge_path)?.sync_all()?;
             }
-            f.file.seek(std::io::SeekFrom::Start(0)).unwrap();
-            let mut reader = BufReader::new(&f.file);
+            fs::rename(merge_path, &path)?;
+            let file = fs::File::open(&path)?;
+            let entries = read_hint_entries(&file).map_err(|e| e.at(id, 0))?;
+            hint::write_hint(&hint_path(&inner.dir, id), &entries)?;
+            for entry in entries {
+                loader.load_hint_entry(id, entry);
+            }
+            merged.push(FilWithId::new(&inner.dir, id.clone(), file, VERSION));
+        }
+        let Loader {
+            key_dir: new, dead, ..
+        } = loader;
+        let merged: Vec<_> = merged
+            .into_iter()
+            .map(|f| {
+                f.dead_bytes
+                    .store(dead.get(&f.id).copied().unwrap_or(0), Ordering::Relaxed);
+                if inner.options.mmap {
+                    f.map();
+                }
+                return Arc::new(f);
+            })
+            .collect();
 
-            let file_sz = f.file.metadata()?.size() as u32;
+        let first_id = outputs.first().map_or(u64::MAX, |id| file_id_num(id));
+        let mut key_dir = inner.key_dir.write().unwrap();
+        let mut files = inner.files.write().unwrap();
+        for (key, val) in new.into_entries() {
+            match key_dir.get_mut(&key) {
+                Some(cur) if file_id_num(&cur.file_id) < first_id => {
+                    let old = std::mem::replace(cur, val);
+                    mark_dead(&files, &old);
+                }
+                _ => mark_dead(&merged, &val),
+            }
+        }
+        key_dir.retain(|val| !replaced.contains(&val.file_id));
 
-            loop {
-                let reader_posi = reader.seek(std::io::SeekFrom::Current(0)).unwrap() as u32;
-                println!("Reader poosi: {}", reader_posi);
+        let old: Vec<_> = files
+            .iter()
+            .filter(|f| replaced.contains(&f.id))
+            .cloned()
+            .collect();
+        files.retain(|f| !replaced.contains(&f.id));
+        let at = files.partition_point(|f| file_id_num(&f.id) < first_id);
+        files.splice(at..at, merged);
 
-                if reader_posi >= file_sz {
-                    break;
+        // replaced files go like on the primary, oldest first once unread
+        *retired = old.iter().map(Arc::downgrade).collect();
+        for pair in old.windows(2) {
+            let _ = pair[0].newer_replaced.set(pair[1].clone());
+        }
+        for f in old {
+            f.obsolete.store(true, Ordering::Release);
+        }
+        return Ok(());
+    }
+
+    /// Makes a replica writable. What the primary sent of an incomplete record
+    /// or batch is cut off, and appends continue in a new file so they never
+    /// mix with what the old primary may have written to the same one.
+    pub(crate) fn promote(&self, tail: Tail) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        if let Some(active) = writer.active.clone() {
+            let end = tail.complete_upto();
+            if end < writer.cur_posi {
+                warn!(
+                    "Cut off {} bytes of an incomplete record at {}:{end}",
+                    writer.cur_posi - end,
+                    active.id
+                );
+                active.file.set_len(end as u64)?;
+                (&active.file).seek(SeekFrom::Start(end as u64))?;
+                writer.cur_posi = end;
+            }
+        }
+        let id = writer.ids.new_id()?;
+        self.rotate(&mut writer, id)?;
+        self.inner.replica.store(false, Ordering::Release);
+        return Ok(());
+    }
+
+    /// Asks the background compaction thread to run a merge and returns right away
+    pub fn merge_in_background(&self) -> Result<()> {
+        match &self.inner.compactor {
+            Some(compactor) => compactor.trigger(),
+            None => return Err(Error::ReadOnly),
+        }
+        return Ok(());
+    }
+
+    /// Rewrites the live records of every file into new files and drops the old
+    /// ones. Writers are only held up while the file set is snapshotted, records
+    /// put meanwhile win over the merged copies.
+    pub fn merge_and_compact(&self) -> Result<()> {
+        return self.merge(None);
+    }
+
+    /// Drops the keys that expired since they were written, their records count
+    /// as garbage from then on like overwritten ones
+    fn remove_expired(&self) {
+        let mut key_dir = self.inner.key_dir.write().unwrap();
+        let files = self.inner.files.read().unwrap();
+        for val in key_dir.remove_expired(record::now_secs()) {
+            mark_dead(&files, &val);
+        }
+    }
+
+    /// Merges the files `policy` picks, or all of them without one
+    fn merge(&self, policy: Option<&CompactionPolicy>) -> Result<()> {
+        let inner = &self.inner;
+        let mut retired = inner.merging.lock().unwrap();
+
+        // until they are deleted the replaced files could bring back keys whose
+        // tombstones the merge dropped if we crashed
+        if retired.iter().any(|f| f.strong_count() > 0) {
+            info!("Files of the previous merge are still being read, skipping merge");
+            return Ok(());
+        }
+
+        // merged files get ids between the last file at the time of the snapshot and
+        // the new active file. They only hold records that were live then, so every
+        // older copy of their keys sorts before them and every newer one after.
+        let (snapshot, full, mut next_id, end_id) = {
+            let mut writer = self.writer()?;
+            let files = inner.files.read().unwrap().clone();
+            let active = writer.active()?.clone();
+
+            let snapshot: Vec<Arc<FilWithId>> = match policy {
+                None if files.len() == 1 && writer.cur_posi <= HEADER_SZ => vec![],
+                None => files.clone(),
+                Some(policy) => pick_files(&files, &active, policy)?,
+            };
+            if snapshot.is_empty() {
+                return Ok(());
+            }
+
+            // each output but the last holds at least its room past the header in
+            // records, which open made sure isn't 0. The slack covers records of
+            // older formats growing when they are rewritten. Values decompressed
+            // for different compression settings can grow past it, the merge then
+            // stops where it got and leaves the rest for the next one.
+            let mut snapshot_sz = 0;
+            for f in snapshot.iter() {
+                snapshot_sz += f.file.metadata()?.size();
+            }
+            let room = (inner.options.max_file_sz - HEADER_SZ) as u64;
+            let reserved = snapshot_sz * 2 / room + 2;
+
+            let first_id = writer.ids.reserve(reserved + 1)?;
+            let end_id = first_id + reserved;
+            self.rotate(&mut writer, end_id.to_string())?;
+
+            // with every file but the new active one merged, no older file is left
+            // for tombstones to shadow
+            let full = snapshot.len() == files.len();
+            (snapshot, full, first_id - 1, end_id)
+        };
+
+        let mut outputs: Vec<MergeOutput> = vec![];
+        // key, its location in the snapshot and its location in the merged files
+        let mut moved: Vec<(Vec<u8>, KeyDirValue, KeyDirValue)> = vec![];
+        // keys whose live record expired, with the location they are dropped from
+        let mut expired: Vec<(Vec<u8>, KeyDirValue)> = vec![];
+        let mut kept_tombstones: HashSet<Vec<u8>> = HashSet::new();
+        let now = record::now_secs();
+
+        // copies the live records into the outputs, returning the file it ran out
+        // of reserved ids in
+        let copied = (|| -> Result<Option<usize>> {
+            for (i, f) in snapshot.iter().enumerate() {
+                let mut reader = BufReader::new(&f.file);
+                reader.seek(SeekFrom::Start(record::records_start(f.version) as u64))?;
+
+                let file_sz = f.file.metadata()?.size() as u32;
+                // a batch can't span files, one without its commit never happened
+                let mut batches = Batches::default();
+
+                loop {
+                    let reader_posi = reader.stream_position()? as u32;
+                    if reader_posi >= file_sz {
+                        break;
+                    }
+                    // records from older formats are rewritten in the current one
+                    let record = match Record::from_reader(&mut reader, f.version) {
+                        Ok((record, _)) => record,
+                        Err(e) => {
+                            warn!(
+                                "Got error when reading row at {}:{reader_posi}: {e}. Ignoring it",
+                                f.id
+                            );
+                            continue;
+                        }
+                    };
+                    let record_sz = reader.stream_position()? as u32 - reader_posi;
+
+                    // merged records are written one by one, the ones of batches load
+                    // discarded are left behind like the markers
+                    let mut applied = vec![];
+                    batches.push(&f.id, record, reader_posi, record_sz, |record, at, _| {
+                        applied.push((record, at));
+                        return Ok(());
+                    })?;
+
+                    for (mut record, reader_posi) in applied {
+                        let mut old = match inner.key_dir.read().unwrap().get(&record.key) {
+                            Some(v) if v.file_id == f.id && v.record_posi == reader_posi => {
+                                Some(v.clone())
+                            }
+                            Some(_) => continue,
+                            None => None,
+                        };
+                        let is_expired = record.is_expired(now);
+                        if is_expired {
+                            if let Some(old) = old.take() {
+                                expired.push((record.key.clone(), old));
+                            }
+                        }
+
+                        if old.is_none() {
+                            // files left out of the merge can still hold the deleted key, so
+                            // a tombstone has to stay behind to hide it
+                            if full || !(record.is_tombstone() || is_expired) {
+                                continue;
+                            }
+                            if !kept_tombstones.insert(record.key.clone()) {
+                                continue;
+                            }
+                            if is_expired {
+                                record = Record::tombstone(record.key);
+                            }
+                        }
+
+                        // old records are brought to the current compression settings
+                        record.decompress().map_err(|e| e.at(&f.id, reader_posi))?;
+                        if let Some(compression) = &inner.options.compression {
+                            record.compress(compression)?;
+                        }
+
+                        if outputs
+                            .last()
+                            .is_none_or(|o| o.posi >= inner.options.max_file_sz)
+                        {
+                            if next_id + 1 >= end_id {
+                                return Ok(Some(i));
+                            }
+                            next_id += 1;
+                            let id = next_id.to_string();
+                            let file = open_path(&inner.dir.join(format!("{id}.merge")))?;
+                            outputs.push(MergeOutput {
+                                id,
+                                file,
+                                posi: HEADER_SZ,
+                                hint_entries: vec![],
+                            });
+                        }
+                        let out = outputs.last_mut().unwrap();
+
+                        let serialized = record.serialize();
+                        out.file.write_all(&serialized)?;
+                        let new = KeyDirValue {
+                            file_id: out.id.clone(),
+                            value_sz: record.value.len() as u32,
+                            record_posi: out.posi,
+                            record_sz: serialized.len() as u32,
+                            expires_at: record.expires_at,
+                        };
+                        out.hint_entries.push(HintEntry {
+                            flags: record.flags,
+                            ts: record.ts,
+                            expires_at: record.expires_at,
+                            key: record.key.clone(),
+                            value_sz: new.value_sz,
+                            record_posi: new.record_posi,
+                            record_sz: new.record_sz,
+                        });
+                        out.posi += serialized.len() as u32;
+                        if let Some(old) = old {
+                            moved.push((record.key, old, new));
+                        }
+                    }
                 }
-                let record = Record::from_reader(&mut reader);
-                if record.is_err() {
-                    println!("Got error when reading row. Ignoring it");
-                    continue;
+            }
+            return Ok(None);
+        })();
+        let stopped_in = match copied {
+            Ok(stopped_in) => stopped_in,
+            Err(e) => {
+                for out in outputs.iter() {
+                    let _ = fs::remove_file(inner.dir.join(format!("{}.merge", out.id)));
                 }
-                let record = record.unwrap();
-                let key_dir_value = self.key_dir.get(&record.key);
-                println!("Record inside merge {:?}", record);
-
-                if let Some(key_dir_value) = key_dir_value {
-                    println!(
-                        "Key dir value inside merge {:?}\nReader posi {}",
-                        key_dir_value, reader_posi
-                    );
-                    if key_dir_value.file_id == f.id && key_dir_value.value_posi == reader_posi {
-                        let written = new_file.write(&record.serialize())? as u32;
-                        self.key_dir.insert(
-                            record.key,
-                            KeyDirValue {
-                                file_id: id.clone(),
-                                value_sz: record.value.len() as u32,
-                                value_posi: writer_posi,
-                            },
-                        );
-                        writer_posi += written;
-                    };
-                };
+                return Err(e);
             }
-            processed_ids.push(f.id.clone());
+        };
+        // the file the merge stopped in still holds records it didn't get to, it
+        // and the newer ones stay
+        let (replaced, kept) = snapshot.split_at(stopped_in.unwrap_or(snapshot.len()));
+
+        let mut merged = vec![];
+        for out in outputs {
+            if inner.options.sync != SyncPolicy::Os {
+                out.file.sync_all()?;
+            }
+            fs::rename(
+                inner.dir.join(format!("{}.merge", out.id)),
+                inner.dir.join(&out.id),
+            )?;
+            hint::write_hint(&hint_path(&inner.dir, &out.id), &out.hint_entries)?;
+            let f = FilWithId::new(&inner.dir, out.id, out.file, VERSION);
+            if inner.options.mmap {
+                f.map();
+            }
+            merged.push(Arc::new(f));
         }
 
-        self.files.retain(|f| !processed_ids.contains(&f.id));
-        self.files.insert(0, FilWithId { file: new_file, id });
+        {
+            // readers looking up a key hold key_dir while they find its file, so they
+            // either see the old location with the old file or the new one with the new
+            let mut key_dir = inner.key_dir.write().unwrap();
+            let mut files = inner.files.write().unwrap();
+
+            for (key, old, new) in moved {
+                // keys put or deleted since they were copied keep their newer value
+                match key_dir.get_mut(&key) {
+                    Some(cur)
+                        if cur.file_id == old.file_id && cur.record_posi == old.record_posi =>
+                    {
+                        *cur = new;
+                        mark_dead(&files, &old);
+                    }
+                    _ => mark_dead(&merged, &new),
+                }
+            }
+            for (key, old) in expired {
+                if let Some(cur) = key_dir.get(&key) {
+                    if cur.file_id == old.file_id && cur.record_posi == old.record_posi {
+                        key_dir.remove(&key);
+                        mark_dead(&files, &old);
+                    }
+                }
+            }
 
-        for pid in processed_ids {
-            fs::remove_file(format!("{DIR}/{pid}"))?
+            files.retain(|f| !replaced.iter().any(|s| s.id == f.id));
+            // files are kept sorted by id, which puts the merged ones right before
+            // whatever was rotated in since the snapshot
+            let at = files.partition_point(|f| file_id_num(&f.id) < end_id);
+            files.splice(at..at, merged.iter().cloned());
+            self.publish_merge(&merged, replaced);
+        }
+
+        info!(
+            "Merged {} files into {}",
+            replaced.len(),
+            merged
+                .iter()
+                .map(|f| f.id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if !kept.is_empty() {
+            info!(
+                "Merge ran out of the file ids reserved for it, left {} files for the next one",
+                kept.len()
+            );
+        }
+        // gets that already grabbed an old file keep it open, it is unlinked when they let go
+        *retired = replaced.iter().map(Arc::downgrade).collect();
+        for pair in replaced.windows(2) {
+            let _ = pair[0].newer_replaced.set(pair[1].clone());
+        }
+        for f in replaced {
+            f.obsolete.store(true, Ordering::Release);
         }
 
         return Ok(());
     }
 }
+
+/// The hint of a file written by a merge, read from its records
+fn read_hint_entries(file: &fs::File) -> Result<Vec<HintEntry>> {
+    let file_sz = file.metadata()?.size() as u32;
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(record::records_start(VERSION) as u64))?;
+    let mut entries = vec![];
+    loop {
+        let record_posi = reader.stream_position()? as u32;
+        if record_posi >= file_sz {
+            break;
+        }
+        let (record, value_sz) = Record::from_reader(&mut reader, VERSION)?;
+        entries.push(HintEntry {
+            flags: record.flags,
+            ts: record.ts,
+            expires_at: record.expires_at,
+            key: record.key,
+            value_sz,
+            record_posi,
+            record_sz: reader.stream_position()? as u32 - record_posi,
+        });
+    }
+    return Ok(entries);
+}
+
+/// Files `policy` wants merged. The active file is left alone unless the store
+/// outgrew its disk limit, in which case every file holding garbage is merged.
+/// Without any there's nothing a merge could free.
+fn pick_files(
+    files: &[Arc<FilWithId>],
+    active: &FilWithId,
+    policy: &CompactionPolicy,
+) -> Result<Vec<Arc<FilWithId>>> {
+    let mut picked = vec![];
+    let mut with_garbage = vec![];
+    let mut disk_usage = 0;
+    for f in files {
+        let stats = f.stats()?;
+        disk_usage += stats.total_bytes;
+        if f.id != active.id && stats.garbage_ratio() >= policy.garbage_ratio {
+            picked.push(f.clone());
+        }
+        if stats.dead_bytes > 0 {
+            with_garbage.push(f.clone());
+        }
+    }
+
+    if policy.max_disk_usage.is_some_and(|max| disk_usage > max) {
+        return Ok(with_garbage);
+    }
+    return Ok(picked);
+}
+
+fn spawn_compactor(store: Weak<Inner>, options: &Options) -> Compactor {
+    let interval = options.compaction.as_ref().map(|p| p.check_interval);
+    return Compactor::spawn(interval, move |trigger| {
+        let Some(inner) = store.upgrade() else {
+            return false;
+        };
+        let store = Store { inner };
+        if trigger == Trigger::Timer {
+            store.remove_expired();
+        }
+        // merges would give the replica files its primary doesn't know about
+        if store.is_replica() && trigger == Trigger::Timer {
+            return true;
+        }
+        let policy = match trigger {
+            Trigger::Requested => None,
+            Trigger::Timer => store.inner.options.compaction.clone(),
+        };
+        if let Err(e) = store.merge(policy.as_ref()) {
+            error!("Background merge failed: {e}");
+        }
+        return true;
+    });
+}
+
+fn spawn_refresher(store: Weak<Inner>, interval: Duration) -> Refresher {
+    return Refresher::spawn(interval, move || {
+        let Some(inner) = store.upgrade() else {
+            return false;
+        };
+        if let Err(e) = (Store { inner }).refresh() {
+            error!("Background refresh failed: {e}");
+        }
+        return true;
+    });
+}
@@ -0,0 +1,92 @@
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::error;
+
+use crate::error::Result;
+
+/// Background thread behind `SyncPolicy::Interval`, fsyncs the active file
+/// every interval if anything was written to it since the last sync.
+#[derive(Debug)]
+pub struct Syncer {
+    file: Arc<Mutex<fs::File>>,
+    dirty: Arc<AtomicBool>,
+    stop: mpsc::Sender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub fn spawn(file: fs::File, interval: Duration) -> Self {
+        let file = Arc::new(Mutex::new(file));
+        let dirty = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let thread = {
+            let file = file.clone();
+            let dirty = dirty.clone();
+            thread::spawn(move || loop {
+                let should_stop = match stopped.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => false,
+                    // stop was sent or the store went away, sync one last time and exit
+                    _ => true,
+                };
+
+                if dirty.swap(false, Ordering::AcqRel) {
+                    if let Err(e) = file.lock().unwrap().sync_data() {
+                        error!("Background sync failed: {e}");
+                        dirty.store(true, Ordering::Release);
+                    }
+                }
+
+                if should_stop {
+                    return;
+                }
+            })
+        };
+
+        return Self {
+            file,
+            dirty,
+            stop,
+            thread: Some(thread),
+        };
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Points the thread at a new active file. The previous one is synced first
+    /// since the thread won't look at it again.
+    pub fn set_file(&self, file: fs::File) -> Result<()> {
+        let mut cur = self.file.lock().unwrap();
+        cur.sync_data()?;
+        *cur = file;
+        return Ok(());
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.dirty.store(false, Ordering::Release);
+        if let Err(e) = self.file.lock().unwrap().sync_data() {
+            self.mark_dirty();
+            return Err(e.into());
+        }
+        return Ok(());
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(t) = self.thread.take() {
+            t.join().unwrap();
+        }
+    }
+}
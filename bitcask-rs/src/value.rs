@@ -0,0 +1,76 @@
+use std::{fmt, ops::Deref, ops::Range, sync::Arc};
+
+use memmap2::Mmap;
+
+/// A value returned by `Store::get_value`. Values in memory mapped files are
+/// borrowed from the mapping, which stays alive as long as the value does,
+/// even when a merge has replaced the file since. Others are owned.
+#[derive(Clone)]
+pub struct Value {
+    repr: Repr,
+}
+
+#[derive(Clone)]
+enum Repr {
+    Owned(Vec<u8>),
+    Mapped {
+        mmap: Arc<Mmap>,
+        range: Range<usize>,
+    },
+}
+
+impl Value {
+    pub(crate) fn owned(value: Vec<u8>) -> Self {
+        return Self {
+            repr: Repr::Owned(value),
+        };
+    }
+
+    pub(crate) fn mapped(mmap: Arc<Mmap>, range: Range<usize>) -> Self {
+        return Self {
+            repr: Repr::Mapped { mmap, range },
+        };
+    }
+
+    /// Whether the value borrows from a memory mapped file
+    pub fn is_mapped(&self) -> bool {
+        return matches!(self.repr, Repr::Mapped { .. });
+    }
+
+    /// Copies the value out of the mapping unless it's owned already
+    pub fn into_vec(self) -> Vec<u8> {
+        return match self.repr {
+            Repr::Owned(value) => value,
+            Repr::Mapped { mmap, range } => mmap[range].to_vec(),
+        };
+    }
+}
+
+impl Deref for Value {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        return match &self.repr {
+            Repr::Owned(value) => value,
+            Repr::Mapped { mmap, range } => &mmap[range.clone()],
+        };
+    }
+}
+
+impl AsRef<[u8]> for Value {
+    fn as_ref(&self) -> &[u8] {
+        return self;
+    }
+}
+
+impl PartialEq<[u8]> for Value {
+    fn eq(&self, other: &[u8]) -> bool {
+        return **self == *other;
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "Value(b\"{}\")", self.escape_ascii());
+    }
+}
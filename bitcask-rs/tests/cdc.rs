@@ -0,0 +1,199 @@
+//! Subscriptions replaying the data files and following what is written
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::{thread, time::Duration};
+
+use bitcask::{Change, Error, Position, Store, Subscription, WriteBatch};
+use common::{open, options, TempDir};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The next `n` changes as `(key, value)`
+fn take(subscription: &mut Subscription, n: usize) -> Vec<(String, Option<String>)> {
+    return (0..n).map(|_| as_text(&next(subscription))).collect();
+}
+
+fn next(subscription: &mut Subscription) -> Change {
+    return subscription
+        .recv_timeout(TIMEOUT)
+        .unwrap()
+        .expect("no change");
+}
+
+fn as_text(change: &Change) -> (String, Option<String>) {
+    let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).unwrap();
+    return (text(&change.key), change.value.as_deref().map(text));
+}
+
+fn put(key: &str, value: &str) -> (String, Option<String>) {
+    return (key.to_string(), Some(value.to_string()));
+}
+
+fn delete(key: &str) -> (String, Option<String>) {
+    return (key.to_string(), None);
+}
+
+/// Nothing more arrives for a while
+fn assert_quiet(subscription: &mut Subscription) {
+    let change = subscription
+        .recv_timeout(Duration::from_millis(50))
+        .unwrap();
+    assert_eq!(change, None);
+}
+
+#[test]
+fn subscribe_follows_puts_and_deletes() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+    store.put("before".to_string(), "x".to_string()).unwrap();
+
+    let mut subscription = store.subscribe().unwrap();
+    store.put("a".to_string(), "1".to_string()).unwrap();
+    store.delete("a".to_string()).unwrap();
+    assert_eq!(take(&mut subscription, 2), [put("a", "1"), delete("a")]);
+    assert_quiet(&mut subscription);
+}
+
+#[test]
+fn replay_continues_into_live_changes_without_a_gap() {
+    let dir = TempDir::new();
+    let store = Store::open(dir.path(), options().max_file_size(512)).unwrap();
+    for i in 0..50 {
+        store.put(format!("key{i}"), i.to_string()).unwrap();
+    }
+
+    // writes racing with the subscription are either replayed or sent live
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 50..100 {
+                store.put(format!("key{i}"), i.to_string()).unwrap();
+            }
+        })
+    };
+    let mut subscription = store.subscribe_from(None).unwrap();
+    writer.join().unwrap();
+
+    let expected: Vec<_> = (0..100)
+        .map(|i| put(&format!("key{i}"), &i.to_string()))
+        .collect();
+    assert_eq!(take(&mut subscription, 100), expected);
+    assert_quiet(&mut subscription);
+}
+
+#[test]
+fn resume_from_a_position() {
+    let dir = TempDir::new();
+    let store = Store::open(dir.path(), options().max_file_size(256)).unwrap();
+    for i in 0..20 {
+        store.put(format!("key{i}"), i.to_string()).unwrap();
+    }
+
+    let mut subscription = store.subscribe_from(None).unwrap();
+    let seen: Vec<_> = (0..12).map(|_| next(&mut subscription)).collect();
+    drop(subscription);
+
+    let mut resumed = store.subscribe_from(Some(&seen[11].next)).unwrap();
+    let expected: Vec<_> = (12..20)
+        .map(|i| put(&format!("key{i}"), &i.to_string()))
+        .collect();
+    assert_eq!(take(&mut resumed, 8), expected);
+
+    // a position is where the record after it starts
+    let again = store.subscribe_from(Some(&seen[3].next)).unwrap().next();
+    assert_eq!(as_text(&again.unwrap().unwrap()), put("key4", "4"));
+}
+
+#[test]
+fn batches_are_replayed_whole() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+    store.put("a".to_string(), "1".to_string()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put_bytes(b"b", b"2");
+    batch.delete_bytes(b"a");
+    batch.put_bytes(b"c", b"3");
+    store.write(batch).unwrap();
+    store.put("d".to_string(), "4".to_string()).unwrap();
+
+    let mut subscription = store.subscribe_from(None).unwrap();
+    assert_eq!(
+        take(&mut subscription, 5),
+        [
+            put("a", "1"),
+            put("b", "2"),
+            delete("a"),
+            put("c", "3"),
+            put("d", "4")
+        ]
+    );
+    assert_quiet(&mut subscription);
+
+    // live batches arrive as their changes too
+    let mut batch = WriteBatch::new();
+    batch.put_bytes(b"e", b"5");
+    batch.put_bytes(b"f", b"6");
+    store.write(batch).unwrap();
+    assert_eq!(take(&mut subscription, 2), [put("e", "5"), put("f", "6")]);
+}
+
+#[test]
+fn replay_after_a_restart() {
+    let dir = TempDir::new();
+    let position = {
+        let store = open(&dir);
+        store.put("a".to_string(), "1".to_string()).unwrap();
+        let mut subscription = store.subscribe().unwrap();
+        store.put("b".to_string(), "2".to_string()).unwrap();
+        next(&mut subscription).next
+    };
+
+    let store = open(&dir);
+    let mut subscription = store.subscribe_from(Some(&position)).unwrap();
+    store.put("c".to_string(), "3".to_string()).unwrap();
+    assert_eq!(take(&mut subscription, 1), [put("c", "3")]);
+}
+
+#[test]
+fn positions_that_are_gone() {
+    let dir = TempDir::new();
+    let store = Store::open(dir.path(), options().max_file_size(256)).unwrap();
+    for i in 0..20 {
+        store.put(format!("key{}", i % 3), i.to_string()).unwrap();
+    }
+    let mut subscription = store.subscribe_from(None).unwrap();
+    let first = next(&mut subscription).next;
+
+    let past_the_end = Position {
+        offset: u32::MAX,
+        ..first.clone()
+    };
+    assert!(matches!(
+        store.subscribe_from(Some(&past_the_end)),
+        Err(Error::PositionGone(p)) if p == past_the_end
+    ));
+
+    store.merge_and_compact().unwrap();
+    assert!(matches!(
+        store.subscribe_from(Some(&first)),
+        Err(Error::PositionGone(p)) if p == first
+    ));
+}
+
+#[test]
+fn closing_the_store_ends_subscriptions() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+    let mut subscription = store.subscribe().unwrap();
+    let mut iter = store.subscribe().unwrap();
+    drop(store);
+
+    assert!(matches!(
+        subscription.recv_timeout(TIMEOUT),
+        Err(Error::Closed)
+    ));
+    assert!(iter.next().is_none());
+}
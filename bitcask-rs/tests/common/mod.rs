@@ -0,0 +1,164 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcask::{Error, Options, Store, WriteBatch};
+use proptest::{collection::vec, option, prelude::*};
+
+/// A directory under the system temp dir, removed again when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "bitcask-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        return Self { path };
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn options() -> Options {
+    return Options::default().create_if_missing(true);
+}
+
+pub fn open(dir: &TempDir) -> Store {
+    return Store::open(dir.path(), options()).unwrap();
+}
+
+/// Opens the store in `dir` again once it is closed. A merge of the
+/// background thread can hold on to it for a moment after the last handle
+/// was dropped.
+pub fn reopen(dir: &TempDir, options: Options) -> Store {
+    let start = Instant::now();
+    loop {
+        match Store::open(dir.path(), options.clone()) {
+            Err(Error::Locked { .. }) if start.elapsed() < Duration::from_secs(10) => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            res => return res.unwrap(),
+        }
+    }
+}
+
+/// Data files of the store in `dir`, oldest first
+pub fn data_files(dir: &TempDir) -> Vec<PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        // hints, the lock file and the like carry an extension
+        .filter(|p| p.extension().is_none() && p.is_file())
+        .collect();
+    files.sort_by_key(|p| {
+        p.file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap()
+    });
+    return files;
+}
+
+/// Keys are drawn from a small set so that puts overwrite and deletes hit
+pub const KEYS: u8 = 16;
+
+/// What the store should hold, key to value
+pub type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A change to the store
+#[derive(Debug, Clone)]
+pub enum Op {
+    Put(u8, Vec<u8>),
+    Delete(u8),
+    Batch(Vec<(u8, Option<Vec<u8>>)>),
+    Merge,
+}
+
+pub fn key(k: u8) -> Vec<u8> {
+    return format!("key{k}").into_bytes();
+}
+
+/// Random bytes, or one byte repeated so that compression kicks in
+pub fn value() -> impl Strategy<Value = Vec<u8>> {
+    return prop_oneof![
+        vec(any::<u8>(), 0..64),
+        (any::<u8>(), 0..200usize).prop_map(|(b, n)| vec![b; n]),
+    ];
+}
+
+pub fn op() -> impl Strategy<Value = Op> {
+    return prop_oneof![
+        6 => (0..KEYS, value()).prop_map(|(k, v)| Op::Put(k, v)),
+        3 => (0..KEYS).prop_map(Op::Delete),
+        2 => vec((0..KEYS, option::of(value())), 1..4).prop_map(Op::Batch),
+        1 => Just(Op::Merge),
+    ];
+}
+
+/// Applies `op` to both the store and the model
+pub fn apply(store: &Store, model: &mut Model, op: &Op) {
+    match op {
+        Op::Put(k, v) => {
+            store.put_bytes(&key(*k), v).unwrap();
+            model.insert(key(*k), v.clone());
+        }
+        Op::Delete(k) => {
+            store.delete_bytes(&key(*k)).unwrap();
+            model.remove(&key(*k));
+        }
+        Op::Batch(writes) => {
+            let mut batch = WriteBatch::new();
+            for (k, v) in writes {
+                match v {
+                    Some(v) => {
+                        batch.put_bytes(&key(*k), v);
+                        model.insert(key(*k), v.clone());
+                    }
+                    None => {
+                        batch.delete_bytes(&key(*k));
+                        model.remove(&key(*k));
+                    }
+                }
+            }
+            store.write(batch).unwrap();
+        }
+        Op::Merge => store.merge_and_compact().unwrap(),
+    }
+}
+
+/// Everything the store holds, read back key by key
+pub fn contents(store: &Store) -> bitcask::Result<Model> {
+    let mut contents = Model::new();
+    for k in store.keys() {
+        if let Some(v) = store.get_bytes(&k)? {
+            contents.insert(k, v);
+        }
+    }
+    return Ok(contents);
+}
@@ -0,0 +1,125 @@
+//! Data files cut short or with a flipped bit, as a crash or a bad disk leaves
+//! them. Opening the store either brings back what it held after some prefix
+//! of the writes, or reports the damage, it never hands out other values.
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs::{self, OpenOptions};
+
+use bitcask::{Error, Options, Store};
+use common::{apply, contents, data_files, op, Model, Op, TempDir};
+use proptest::{collection::vec, prelude::*, sample::Index};
+
+fn options() -> Options {
+    return Options::default()
+        .create_if_missing(true)
+        .max_file_size(1024);
+}
+
+/// Applies `ops` to a fresh store in `dir` and closes it, returning what it
+/// held after each prefix of them
+fn write(dir: &TempDir, ops: &[Op]) -> Vec<Model> {
+    let store = Store::open(dir.path(), options()).unwrap();
+    let mut model = Model::new();
+    let mut prefixes = vec![model.clone()];
+    for op in ops {
+        apply(&store, &mut model, op);
+        prefixes.push(model.clone());
+    }
+    return prefixes;
+}
+
+/// Damage the store noticed, instead of returning wrong data
+fn is_detected(e: &Error) -> bool {
+    return matches!(e, Error::Corruption { .. } | Error::Io(_));
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn truncated_active_file_recovers_a_prefix(ops in vec(op(), 1..60), cut in any::<Index>()) {
+        let dir = TempDir::new();
+        let prefixes = write(&dir, &ops);
+
+        let active = data_files(&dir).pop().unwrap();
+        let len = fs::metadata(&active).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&active).unwrap();
+        file.set_len(cut.index(len as usize + 1) as u64).unwrap();
+        drop(file);
+
+        let store = Store::open(dir.path(), options()).unwrap();
+        let recovered = contents(&store).unwrap();
+        prop_assert!(prefixes.contains(&recovered), "{:?} is no prefix", recovered);
+
+        // the store keeps working after what was cut off
+        store.put_bytes(b"after", b"crash").unwrap();
+        drop(store);
+        let store = Store::open(dir.path(), options()).unwrap();
+        prop_assert!(store.recovery().is_none());
+        let mut expected = recovered;
+        expected.insert(b"after".to_vec(), b"crash".to_vec());
+        prop_assert_eq!(contents(&store).unwrap(), expected);
+    }
+
+    #[test]
+    fn flipped_bit_is_detected_or_recovers_a_prefix(
+        ops in vec(op(), 1..60),
+        file in any::<Index>(),
+        offset in any::<Index>(),
+        bit in 0..8u8,
+    ) {
+        let dir = TempDir::new();
+        let prefixes = write(&dir, &ops);
+
+        let files = data_files(&dir);
+        let path = file.get(&files);
+        let mut bytes = fs::read(path).unwrap();
+        prop_assume!(!bytes.is_empty());
+        let offset = offset.index(bytes.len());
+        bytes[offset] ^= 1 << bit;
+        fs::write(path, bytes).unwrap();
+
+        match Store::open(dir.path(), options()).and_then(|store| contents(&store)) {
+            Ok(recovered) => {
+                prop_assert!(prefixes.contains(&recovered), "{:?} is no prefix", recovered);
+            }
+            Err(e) => prop_assert!(is_detected(&e), "{:?}", e),
+        }
+    }
+}
+
+/// Offset right after the varint at `posi`
+fn skip_varint(bytes: &[u8], mut posi: usize) -> usize {
+    while bytes[posi] & 0x80 != 0 {
+        posi += 1;
+    }
+    return posi + 1;
+}
+
+#[test]
+fn damage_before_good_records_is_not_truncated() {
+    let dir = TempDir::new();
+    {
+        let store = Store::open(dir.path(), options()).unwrap();
+        for i in 0..5 {
+            store.put(format!("key{i}"), "value".to_string()).unwrap();
+        }
+    }
+
+    // the key size of the first record, past the header, crc, flags and ts
+    let path = data_files(&dir).pop().unwrap();
+    let mut bytes = fs::read(&path).unwrap();
+    let crc_end = skip_varint(&bytes, 5);
+    let key_sz_at = skip_varint(&bytes, crc_end + 1);
+    bytes[key_sz_at] = 0x7f;
+    fs::write(&path, &bytes).unwrap();
+
+    match Store::open(dir.path(), options()) {
+        Err(Error::Corruption { offset, .. }) => assert_eq!(offset, 5),
+        other => panic!("{other:?}"),
+    }
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+}
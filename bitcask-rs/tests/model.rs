@@ -0,0 +1,76 @@
+//! Random sequences of puts, deletes, batches, merges and restarts, checked
+//! against a map holding what the store should
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use bitcask::{Codec, Compression, Options, Store};
+use common::{apply, contents, key, op, Model, Op, TempDir, KEYS};
+use proptest::{collection::vec, prelude::*};
+
+#[derive(Debug, Clone)]
+enum Step {
+    Write(Op),
+    Get(u8),
+    Reopen,
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    return prop_oneof![
+        8 => op().prop_map(Step::Write),
+        3 => (0..KEYS).prop_map(Step::Get),
+        1 => Just(Step::Reopen),
+    ];
+}
+
+/// Small files so the steps span several of them, with every way of indexing,
+/// reading and storing values
+fn options() -> impl Strategy<Value = Options> {
+    return (any::<bool>(), any::<bool>(), any::<bool>()).prop_map(
+        |(ordered_index, mmap, compress)| {
+            let options = Options::default()
+                .create_if_missing(true)
+                .max_file_size(1024)
+                .ordered_index(ordered_index)
+                .mmap(mmap);
+            if !compress {
+                return options;
+            }
+            return options.compression(Compression {
+                codec: Codec::Lz4,
+                min_value_sz: 32,
+            });
+        },
+    );
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn matches_model(options in options(), steps in vec(step(), 1..120)) {
+        let dir = TempDir::new();
+        let mut store = Store::open(dir.path(), options.clone()).unwrap();
+        let mut model = Model::new();
+
+        for step in &steps {
+            match step {
+                Step::Write(op) => apply(&store, &mut model, op),
+                Step::Get(k) => {
+                    prop_assert_eq!(store.get_bytes(&key(*k)).unwrap(), model.get(&key(*k)).cloned());
+                }
+                Step::Reopen => {
+                    drop(store);
+                    store = Store::open(dir.path(), options.clone()).unwrap();
+                    prop_assert!(store.recovery().is_none());
+                }
+            }
+        }
+        prop_assert_eq!(contents(&store).unwrap(), model.clone());
+
+        drop(store);
+        let store = Store::open(dir.path(), options).unwrap();
+        prop_assert_eq!(contents(&store).unwrap(), model);
+    }
+}
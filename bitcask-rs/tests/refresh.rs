@@ -0,0 +1,137 @@
+//! Read only stores picking up what a writer did since they were opened
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+
+use bitcask::{
+    inspect::{self, Entry},
+    Options, Store, WriteBatch,
+};
+use common::{data_files, options, TempDir};
+
+fn get(store: &Store, key: &str) -> Option<String> {
+    return store.get(key.to_string()).unwrap();
+}
+
+fn reader(dir: &TempDir) -> Store {
+    return Store::open(dir.path(), Options::default().read_only(true)).unwrap();
+}
+
+#[test]
+fn refresh_picks_up_puts_and_deletes() {
+    let dir = TempDir::new();
+    let writer = Store::open(dir.path(), options()).unwrap();
+    writer.put("a".to_string(), "1".to_string()).unwrap();
+    writer.put("b".to_string(), "2".to_string()).unwrap();
+    let reader = reader(&dir);
+
+    writer.put("a".to_string(), "3".to_string()).unwrap();
+    writer.delete("b".to_string()).unwrap();
+    writer.put("c".to_string(), "4".to_string()).unwrap();
+    assert_eq!(get(&reader, "a").as_deref(), Some("1"));
+
+    reader.refresh().unwrap();
+    assert_eq!(get(&reader, "a").as_deref(), Some("3"));
+    assert_eq!(get(&reader, "b"), None);
+    assert_eq!(get(&reader, "c").as_deref(), Some("4"));
+
+    // overwritten and deleted values count as garbage like after a load
+    let stats = reader.stats().unwrap();
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.dead_bytes(), writer.stats().unwrap().dead_bytes());
+
+    // and a key put back after a delete
+    writer.put("b".to_string(), "5".to_string()).unwrap();
+    reader.refresh().unwrap();
+    assert_eq!(get(&reader, "b").as_deref(), Some("5"));
+}
+
+#[test]
+fn refresh_picks_up_new_files() {
+    let dir = TempDir::new();
+    let options = || options().max_file_size(256);
+    let writer = Store::open(dir.path(), options()).unwrap();
+    writer.put("key0".to_string(), "x".to_string()).unwrap();
+    let reader = Store::open(dir.path(), options().read_only(true).mmap(true)).unwrap();
+
+    for i in 0..40 {
+        writer.put(format!("key{}", i % 7), i.to_string()).unwrap();
+        if i % 5 == 0 {
+            reader.refresh().unwrap();
+        }
+    }
+    writer.delete("key3".to_string()).unwrap();
+    reader.refresh().unwrap();
+    for i in 33..40 {
+        let key = format!("key{}", i % 7);
+        let expected = (key != "key3").then(|| i.to_string());
+        assert_eq!(get(&reader, &key), expected);
+    }
+    assert_eq!(
+        reader.stats().unwrap().dead_bytes(),
+        writer.stats().unwrap().dead_bytes()
+    );
+}
+
+#[test]
+fn refresh_after_a_merge() {
+    let dir = TempDir::new();
+    let options = || options().max_file_size(256);
+    let writer = Store::open(dir.path(), options()).unwrap();
+    for i in 0..40 {
+        writer.put(format!("key{}", i % 7), i.to_string()).unwrap();
+    }
+    let reader = Store::open(dir.path(), options().read_only(true)).unwrap();
+
+    writer.delete("key0".to_string()).unwrap();
+    writer.merge_and_compact().unwrap();
+    writer.put("key1".to_string(), "after".to_string()).unwrap();
+    reader.refresh().unwrap();
+    assert_eq!(get(&reader, "key0"), None);
+    assert_eq!(get(&reader, "key1").as_deref(), Some("after"));
+    assert_eq!(get(&reader, "key6").as_deref(), Some("34"));
+    assert_eq!(reader.keys().count(), 6);
+}
+
+#[test]
+fn refresh_waits_for_the_commit_of_a_batch() {
+    let dir = TempDir::new();
+    let writer = Store::open(dir.path(), options()).unwrap();
+    writer.put("a".to_string(), "1".to_string()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put_bytes(b"a", b"2");
+    batch.put_bytes(b"b", b"3");
+    writer.write(batch).unwrap();
+    drop(writer);
+
+    // cut the commit off, as if the writer was still appending it
+    let path = data_files(&dir).pop().unwrap();
+    let commit = inspect::DataFile::open(&path)
+        .unwrap()
+        .entries()
+        .find_map(|entry| match entry {
+            Entry::Record(r) if r.kind() == "batch-commit" => Some(r),
+            _ => None,
+        })
+        .unwrap();
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..commit.offset as usize]).unwrap();
+
+    let reader = reader(&dir);
+    assert_eq!(get(&reader, "a").as_deref(), Some("1"));
+    assert_eq!(get(&reader, "b"), None);
+    assert_eq!(reader.stats().unwrap().dead_bytes(), 0);
+
+    // the batch is read again once committed
+    fs::write(&path, &bytes).unwrap();
+    reader.refresh().unwrap();
+    assert_eq!(get(&reader, "a").as_deref(), Some("2"));
+    assert_eq!(get(&reader, "b").as_deref(), Some("3"));
+    assert_eq!(
+        reader.stats().unwrap().dead_bytes(),
+        self::reader(&dir).stats().unwrap().dead_bytes()
+    );
+}
@@ -0,0 +1,136 @@
+//! Replicas following a primary over a local connection
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::{
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcask::{serve_replicas, Replica, ReplicaStatus, Store};
+use common::{data_files, open, options, TempDir};
+
+/// Waits until `f` holds, failing the test after a while
+fn eventually(f: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !f() {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn has(store: &Store, key: &str, value: &str) -> bool {
+    return store.get(key.to_string()).unwrap().as_deref() == Some(value);
+}
+
+#[test]
+fn replica_follows_primary() {
+    let (primary_dir, replica_dir) = (TempDir::new(), TempDir::new());
+    let primary = open(&primary_dir);
+    primary.put("a".to_string(), "1".to_string()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let _server = serve_replicas(&primary, listener).unwrap();
+    let replica = Replica::start(replica_dir.path(), &addr, options()).unwrap();
+    eventually(|| has(replica.store(), "a", "1"));
+
+    primary.put("b".to_string(), "2".to_string()).unwrap();
+    eventually(|| has(replica.store(), "b", "2"));
+}
+
+/// Names of the data files in `dir`
+fn file_names(dir: &TempDir) -> Vec<String> {
+    return data_files(dir)
+        .iter()
+        .map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
+        .collect();
+}
+
+#[test]
+fn replica_follows_merges_of_the_primary() {
+    let (primary_dir, replica_dir) = (TempDir::new(), TempDir::new());
+    let primary = Store::open(primary_dir.path(), options().max_file_size(256)).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let _server = serve_replicas(&primary, listener).unwrap();
+    let replica = Replica::start(replica_dir.path(), &addr, options()).unwrap();
+    eventually(|| replica.status() == ReplicaStatus::Following);
+
+    for i in 0..40 {
+        primary
+            .put(format!("key{}", i % 4), format!("{i}{}", "x".repeat(40)))
+            .unwrap();
+    }
+    primary.delete("key0".to_string()).unwrap();
+    // nothing is put after the merge, the replica still moves to the new active
+    // file and swaps in the merged ones
+    primary.merge_and_compact().unwrap();
+    eventually(|| file_names(&replica_dir) == file_names(&primary_dir));
+    let value = |i: usize| format!("{i}{}", "x".repeat(40));
+    for (key, i) in [("key1", 37), ("key2", 38), ("key3", 39)] {
+        assert!(has(replica.store(), key, &value(i)));
+    }
+    assert_eq!(replica.store().get("key0".to_string()).unwrap(), None);
+    assert_eq!(replica.store().stats().unwrap().dead_bytes(), 0);
+    drop(replica);
+
+    // a restarted replica continues from the file the primary rotated into
+    let replica = Replica::start(replica_dir.path(), &addr, options()).unwrap();
+    eventually(|| replica.status() == ReplicaStatus::Following);
+    primary.put("key0".to_string(), "back".to_string()).unwrap();
+    eventually(|| has(replica.store(), "key0", "back"));
+    assert!(has(replica.store(), "key3", &value(39)));
+}
+
+#[test]
+fn serving_replicas_does_not_keep_the_primary_open() {
+    let (primary_dir, replica_dir) = (TempDir::new(), TempDir::new());
+    let primary = open(&primary_dir);
+    primary.put("a".to_string(), "1".to_string()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = serve_replicas(&primary, listener).unwrap();
+    let replica = Replica::start(replica_dir.path(), &addr, options()).unwrap();
+    eventually(|| has(replica.store(), "a", "1"));
+
+    // the replica is still connected, and the server still running
+    drop(primary);
+    let primary = open(&primary_dir);
+    assert!(has(&primary, "a", "1"));
+
+    drop(server);
+    assert!(TcpStream::connect(&addr).is_err());
+}
+
+#[test]
+fn replica_gives_up_once_its_position_is_merged_away() {
+    let (primary_dir, replica_dir) = (TempDir::new(), TempDir::new());
+    let primary = Store::open(primary_dir.path(), options().max_file_size(256)).unwrap();
+    primary.put("a".to_string(), "1".to_string()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let _server = serve_replicas(&primary, listener).unwrap();
+    let replica = Replica::start(replica_dir.path(), &addr, options()).unwrap();
+    eventually(|| replica.status() == ReplicaStatus::Following);
+    eventually(|| has(replica.store(), "a", "1"));
+    drop(replica);
+
+    for i in 0..20 {
+        primary
+            .put(format!("key{}", i % 2), "x".repeat(50))
+            .unwrap();
+    }
+    primary.merge_and_compact().unwrap();
+
+    let replica = Replica::start(replica_dir.path(), &addr, options()).unwrap();
+    eventually(|| matches!(replica.status(), ReplicaStatus::PositionGone(_)));
+    assert!(has(replica.store(), "a", "1"));
+    let store = replica.promote().unwrap();
+    store.put("b".to_string(), "2".to_string()).unwrap();
+}
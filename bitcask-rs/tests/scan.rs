@@ -0,0 +1,131 @@
+//! Keys, prefix scans and ranges over both kinds of index
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::{
+    ops::{Bound, RangeBounds},
+    thread,
+    time::Duration,
+};
+
+use bitcask::{Options, Store};
+use common::{options, TempDir};
+
+/// Runs `f` against a store with a hash index and one with an ordered index
+fn with_both_indexes(f: impl Fn(&Store)) {
+    for ordered_index in [false, true] {
+        let dir = TempDir::new();
+        let options: Options = options().ordered_index(ordered_index);
+        f(&Store::open(dir.path(), options).unwrap());
+    }
+}
+
+fn put(store: &Store, keys: &[&[u8]]) {
+    for key in keys {
+        store.put_bytes(key, &[key.len() as u8]).unwrap();
+    }
+}
+
+fn scanned(scan: impl Iterator<Item = bitcask::Result<(Vec<u8>, Vec<u8>)>>) -> Vec<Vec<u8>> {
+    return scan.map(|entry| entry.unwrap().0).collect();
+}
+
+#[test]
+fn keys_are_sorted_and_skip_deleted_and_expired() {
+    with_both_indexes(|store| {
+        put(store, &[b"c", b"a", b"b", b"d"]);
+        store.delete_bytes(b"b").unwrap();
+        store
+            .put_bytes_with_ttl(b"e", b"gone", Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(store.keys().count(), 4);
+
+        thread::sleep(Duration::from_millis(2100));
+        assert_eq!(
+            store.keys().collect::<Vec<_>>(),
+            [b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+    });
+}
+
+#[test]
+fn keys_span_several_pages() {
+    with_both_indexes(|store| {
+        let keys: Vec<_> = (0..2500u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        for key in keys.iter().rev() {
+            store.put_bytes(key, b"").unwrap();
+        }
+        assert_eq!(store.keys().collect::<Vec<_>>(), keys);
+        assert_eq!(
+            scanned(store.range(keys[1000].clone()..keys[2200].clone())),
+            keys[1000..2200]
+        );
+    });
+}
+
+#[test]
+fn scan_prefix() {
+    with_both_indexes(|store| {
+        put(store, &[b"ab", b"a", b"abc", b"b", b"aa\xff", b"ab\xff"]);
+        assert_eq!(
+            scanned(store.scan_prefix(b"ab")),
+            [b"ab".to_vec(), b"abc".to_vec(), b"ab\xff".to_vec()]
+        );
+        assert_eq!(scanned(store.scan_prefix(b"")).len(), 6);
+        assert!(scanned(store.scan_prefix(b"c")).is_empty());
+
+        let values: Vec<_> = store.scan_prefix(b"abc").map(|e| e.unwrap().1).collect();
+        assert_eq!(values, [vec![3]]);
+    });
+}
+
+#[test]
+fn scan_prefix_of_all_0xff() {
+    with_both_indexes(|store| {
+        put(store, &[b"\xfe", b"\xff", b"\xff\xff", b"\xff\xff\x00"]);
+        assert_eq!(
+            scanned(store.scan_prefix(b"\xff\xff")),
+            [b"\xff\xff".to_vec(), b"\xff\xff\x00".to_vec()]
+        );
+        assert_eq!(scanned(store.scan_prefix(b"\xff")).len(), 3);
+    });
+}
+
+/// Keys of `store.range(range)`, as strings
+fn range<R: RangeBounds<&'static str>>(store: &Store, range: R) -> Vec<String> {
+    return scanned(store.range::<&str, R>(range))
+        .into_iter()
+        .map(|key| String::from_utf8(key).unwrap())
+        .collect();
+}
+
+#[test]
+fn range_bounds() {
+    with_both_indexes(|store| {
+        put(store, &[b"a", b"b", b"c", b"d"]);
+        assert_eq!(range(store, "b".."d"), ["b", "c"]);
+        assert_eq!(range(store, "b"..="d"), ["b", "c", "d"]);
+        assert_eq!(range(store, "c"..), ["c", "d"]);
+        assert_eq!(range(store, .."b"), ["a"]);
+        assert_eq!(range(store, ..), ["a", "b", "c", "d"]);
+        assert_eq!(
+            range(store, (Bound::Excluded("a"), Bound::Excluded("c"))),
+            ["b"]
+        );
+    });
+}
+
+#[test]
+fn reversed_or_empty_ranges_are_empty() {
+    with_both_indexes(|store| {
+        put(store, &[b"a", b"b", b"c"]);
+        assert!(range(store, "c".."a").is_empty());
+        assert!(range(store, "c"..="a").is_empty());
+        assert!(range(store, "b".."b").is_empty());
+        assert!(range(store, (Bound::Excluded("b"), Bound::Excluded("b"))).is_empty());
+        assert!(range(store, (Bound::Excluded("b"), Bound::Included("b"))).is_empty());
+        assert_eq!(range(store, "b"..="b"), ["b"]);
+    });
+}
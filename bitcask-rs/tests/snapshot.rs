@@ -0,0 +1,112 @@
+//! Snapshots of a store and stores restored from them
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs::{self, OpenOptions};
+
+use bitcask::{Error, Store};
+use common::{data_files, open, options, TempDir};
+
+fn get(store: &Store, key: &str) -> Option<String> {
+    return store.get(key.to_string()).unwrap();
+}
+
+/// A store with a few keys spread over several files
+fn filled(dir: &TempDir) -> Store {
+    let store = Store::open(dir.path(), options().max_file_size(256)).unwrap();
+    for i in 0..30 {
+        store
+            .put(format!("key{}", i % 10), format!("value{i}"))
+            .unwrap();
+    }
+    store.delete("key0".to_string()).unwrap();
+    return store;
+}
+
+#[test]
+fn restore_brings_back_the_store_as_it_was() {
+    let (dir, snapshot, restored) = (TempDir::new(), TempDir::new(), TempDir::new());
+    let store = filled(&dir);
+    store.snapshot(snapshot.path()).unwrap();
+
+    // neither writes nor merges of the store change the snapshot
+    store.put("key1".to_string(), "after".to_string()).unwrap();
+    store.merge_and_compact().unwrap();
+
+    let copy = Store::restore(snapshot.path(), restored.path(), options()).unwrap();
+    assert_eq!(get(&copy, "key0"), None);
+    assert_eq!(get(&copy, "key1").as_deref(), Some("value21"));
+    assert_eq!(get(&copy, "key9").as_deref(), Some("value29"));
+    assert_eq!(copy.keys().count(), 9);
+    assert_eq!(get(&store, "key1").as_deref(), Some("after"));
+
+    // the restored store is writable and survives a restart
+    copy.put("key1".to_string(), "restored".to_string())
+        .unwrap();
+    copy.merge_and_compact().unwrap();
+    drop(copy);
+    let copy = open(&restored);
+    assert!(copy.recovery().is_none());
+    assert_eq!(get(&copy, "key1").as_deref(), Some("restored"));
+    assert_eq!(get(&copy, "key9").as_deref(), Some("value29"));
+
+    // and the snapshot still restores as it was taken
+    let again = TempDir::new();
+    let copy = Store::restore(snapshot.path(), again.path(), options()).unwrap();
+    assert_eq!(get(&copy, "key1").as_deref(), Some("value21"));
+}
+
+#[test]
+fn snapshot_of_an_empty_store() {
+    let (dir, snapshot, restored) = (TempDir::new(), TempDir::new(), TempDir::new());
+    open(&dir).snapshot(snapshot.path()).unwrap();
+    let copy = Store::restore(snapshot.path(), restored.path(), options()).unwrap();
+    assert_eq!(copy.keys().count(), 0);
+    copy.put("a".to_string(), "1".to_string()).unwrap();
+}
+
+#[test]
+fn destination_must_be_empty() {
+    let (dir, snapshot) = (TempDir::new(), TempDir::new());
+    let store = filled(&dir);
+    fs::create_dir(snapshot.path()).unwrap();
+    fs::write(snapshot.path().join("other"), "").unwrap();
+    assert!(matches!(
+        store.snapshot(snapshot.path()),
+        Err(Error::Invalid(_))
+    ));
+
+    let (snapshot, restored) = (TempDir::new(), TempDir::new());
+    store.snapshot(snapshot.path()).unwrap();
+    assert!(matches!(
+        Store::restore(snapshot.path(), dir.path(), options()),
+        Err(Error::Invalid(_))
+    ));
+    fs::create_dir(restored.path()).unwrap();
+    Store::restore(snapshot.path(), restored.path(), options()).unwrap();
+}
+
+#[test]
+fn incomplete_snapshots_are_refused() {
+    let (dir, snapshot) = (TempDir::new(), TempDir::new());
+    let store = filled(&dir);
+    store.snapshot(snapshot.path()).unwrap();
+    let restore = || Store::restore(snapshot.path(), TempDir::new().path(), options());
+
+    // a file cut short
+    let file = data_files(&snapshot).pop().unwrap();
+    let len = fs::metadata(&file).unwrap().len();
+    let handle = OpenOptions::new().write(true).open(&file).unwrap();
+    handle.set_len(len - 1).unwrap();
+    assert!(matches!(restore(), Err(Error::Invalid(_))));
+
+    // a file missing
+    fs::remove_file(&file).unwrap();
+    assert!(matches!(restore(), Err(Error::Invalid(_))));
+
+    // no manifest, the snapshot wasn't finished
+    fs::remove_file(snapshot.path().join("snapshot.manifest")).unwrap();
+    assert!(matches!(restore(), Err(Error::Invalid(_))));
+}
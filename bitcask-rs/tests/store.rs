@@ -0,0 +1,441 @@
+//! Puts, gets and deletes through the public API, across restarts and merges
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use bitcask::{
+    inspect::{self, Entry},
+    Codec, CompactionPolicy, Compression, Error, Options, Store, WriteBatch,
+};
+use common::{data_files, open, options, reopen, TempDir};
+
+#[test]
+fn put_get_delete() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+
+    assert_eq!(store.get("mac".to_string()).unwrap(), None);
+    store
+        .put("mac".to_string(), "a laptop".to_string())
+        .unwrap();
+    assert_eq!(
+        store.get("mac".to_string()).unwrap().as_deref(),
+        Some("a laptop")
+    );
+
+    store
+        .put("mac".to_string(), "a computer".to_string())
+        .unwrap();
+    assert_eq!(
+        store.get("mac".to_string()).unwrap().as_deref(),
+        Some("a computer")
+    );
+
+    store.delete("mac".to_string()).unwrap();
+    assert_eq!(store.get("mac".to_string()).unwrap(), None);
+    // deleting what isn't there is fine
+    store.delete("mac".to_string()).unwrap();
+}
+
+#[test]
+fn binary_keys_and_values() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+
+    let value: Vec<u8> = (0..=255).collect();
+    store.put_bytes(&[0, 0xff, 7], &value).unwrap();
+    store.put_bytes(b"empty", b"").unwrap();
+    assert_eq!(store.get_bytes(&[0, 0xff, 7]).unwrap(), Some(value));
+    assert_eq!(store.get_bytes(b"empty").unwrap(), Some(vec![]));
+
+    store.put_bytes(b"bin", &[0xff, 0xfe]).unwrap();
+    assert!(matches!(
+        store.get("bin".to_string()),
+        Err(Error::Invalid(_))
+    ));
+}
+
+#[test]
+fn survives_restart() {
+    let dir = TempDir::new();
+    {
+        let store = open(&dir);
+        for i in 0..100 {
+            store.put(format!("key{i}"), format!("value{i}")).unwrap();
+        }
+        store
+            .put("key7".to_string(), "changed".to_string())
+            .unwrap();
+        store.delete("key9".to_string()).unwrap();
+    }
+
+    let store = open(&dir);
+    assert!(store.recovery().is_none());
+    assert_eq!(
+        store.get("key7".to_string()).unwrap().as_deref(),
+        Some("changed")
+    );
+    assert_eq!(store.get("key9".to_string()).unwrap(), None);
+    assert_eq!(
+        store.get("key42".to_string()).unwrap().as_deref(),
+        Some("value42")
+    );
+    assert_eq!(store.keys().count(), 99);
+}
+
+#[test]
+fn survives_restart_across_files() {
+    let dir = TempDir::new();
+    let options = || options().max_file_size(1024);
+    {
+        let store = Store::open(dir.path(), options()).unwrap();
+        for i in 0..500 {
+            store
+                .put(format!("key{}", i % 50), format!("value{i}"))
+                .unwrap();
+        }
+    }
+    assert!(data_files(&dir).len() > 1);
+
+    let store = Store::open(dir.path(), options()).unwrap();
+    for i in 450..500 {
+        assert_eq!(
+            store.get(format!("key{}", i % 50)).unwrap(),
+            Some(format!("value{i}"))
+        );
+    }
+}
+
+#[test]
+fn batches_apply_together() {
+    let dir = TempDir::new();
+    {
+        let store = open(&dir);
+        store.put("a".to_string(), "1".to_string()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_bytes(b"b", b"2");
+        batch.delete_bytes(b"a");
+        store.write(batch).unwrap();
+    }
+
+    let store = open(&dir);
+    assert_eq!(store.get("a".to_string()).unwrap(), None);
+    assert_eq!(store.get("b".to_string()).unwrap().as_deref(), Some("2"));
+}
+
+#[test]
+fn partial_merge_leaves_discarded_batches_behind() {
+    let dir = TempDir::new();
+    let options = || options().max_file_size(512);
+    {
+        let store = Store::open(dir.path(), options()).unwrap();
+        store.put("a".to_string(), "1".to_string()).unwrap();
+        for i in 0.. {
+            store.put(format!("filler{i}"), "x".repeat(40)).unwrap();
+            if data_files(&dir).len() == 2 {
+                break;
+            }
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete_bytes(b"a");
+        batch.delete_bytes(b"ghost");
+        store.write(batch).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete_bytes(b"b");
+        store.write(batch).unwrap();
+        while data_files(&dir).len() == 2 {
+            store.put("junk".to_string(), "x".repeat(40)).unwrap();
+        }
+    }
+
+    // without its commit the first batch, deleting `a` and a key that never
+    // existed, didn't happen
+    let (first, path) = (data_files(&dir)[0].clone(), data_files(&dir)[1].clone());
+    let commit = inspect::DataFile::open(&path)
+        .unwrap()
+        .entries()
+        .find_map(|entry| match entry {
+            Entry::Record(r) if r.kind() == "batch-commit" => Some(r),
+            _ => None,
+        })
+        .unwrap();
+    let mut bytes = fs::read(&path).unwrap();
+    let (start, end) = (
+        commit.offset as usize,
+        (commit.offset + commit.size) as usize,
+    );
+    bytes.drain(start..end);
+    fs::write(&path, bytes).unwrap();
+
+    let policy = CompactionPolicy {
+        garbage_ratio: 0.5,
+        max_disk_usage: None,
+        check_interval: Duration::from_millis(10),
+    };
+    let store = Store::open(dir.path(), options().compaction(policy)).unwrap();
+    assert_eq!(store.get("a".to_string()).unwrap().as_deref(), Some("1"));
+    let start = Instant::now();
+    while path.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10), "never merged");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // the first file wasn't merged, yet the merge kept no tombstones of the batch
+    assert!(first.exists());
+    for path in data_files(&dir) {
+        let file = inspect::DataFile::open(&path).unwrap();
+        assert!(!file.entries().any(|entry| match entry {
+            Entry::Record(r) => r.key == b"ghost",
+            _ => false,
+        }));
+    }
+    drop(store);
+
+    let store = reopen(&dir, options());
+    assert_eq!(store.get("a".to_string()).unwrap().as_deref(), Some("1"));
+}
+
+#[test]
+fn over_the_disk_limit_only_files_with_garbage_are_merged() {
+    let dir = TempDir::new();
+    let policy = CompactionPolicy {
+        garbage_ratio: 0.5,
+        max_disk_usage: Some(1024),
+        check_interval: Duration::from_millis(10),
+    };
+    let store = Store::open(dir.path(), options().max_file_size(512).compaction(policy)).unwrap();
+    for i in 0..40 {
+        store.put(format!("key{i}"), "x".repeat(40)).unwrap();
+    }
+
+    // nothing to free, so the files stay as they are however long it waits
+    let files = data_files(&dir);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(data_files(&dir), files);
+
+    // a single overwrite makes its file worth merging, but only that one
+    store.put("key0".to_string(), "y".repeat(40)).unwrap();
+    let start = Instant::now();
+    while files[0].exists() {
+        assert!(start.elapsed() < Duration::from_secs(10), "never merged");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(files[1..files.len() - 1].iter().all(|f| f.exists()));
+    assert_eq!(store.stats().unwrap().dead_bytes(), 0);
+    assert_eq!(store.get("key0".to_string()).unwrap(), Some("y".repeat(40)));
+}
+
+#[test]
+fn merge_keeps_live_values() {
+    let dir = TempDir::new();
+    let options = || options().max_file_size(4096);
+    {
+        let store = Store::open(dir.path(), options()).unwrap();
+        for i in 0..2000 {
+            store
+                .put(format!("key{}", i % 20), format!("value{i}"))
+                .unwrap();
+        }
+        store.delete("key3".to_string()).unwrap();
+        let before = data_files(&dir).len();
+        assert!(store.stats().unwrap().dead_bytes() > 0);
+
+        store.merge_and_compact().unwrap();
+
+        assert!(data_files(&dir).len() < before);
+        assert_eq!(store.stats().unwrap().dead_bytes(), 0);
+        assert_eq!(store.get("key3".to_string()).unwrap(), None);
+        for i in 1980..2000 {
+            if i % 20 != 3 {
+                assert_eq!(
+                    store.get(format!("key{}", i % 20)).unwrap(),
+                    Some(format!("value{i}"))
+                );
+            }
+        }
+
+        // writes after the merge win over the merged records
+        store.put("key0".to_string(), "after".to_string()).unwrap();
+    }
+
+    let store = Store::open(dir.path(), options()).unwrap();
+    assert_eq!(store.keys().count(), 19);
+    assert_eq!(store.get("key3".to_string()).unwrap(), None);
+    assert_eq!(
+        store.get("key0".to_string()).unwrap().as_deref(),
+        Some("after")
+    );
+    assert_eq!(
+        store.get("key19".to_string()).unwrap().as_deref(),
+        Some("value1999")
+    );
+}
+
+#[test]
+fn merge_of_values_growing_past_the_files_reserved_for_it() {
+    let dir = TempDir::new();
+    let value = |i: usize| format!("{i}{}", "x".repeat(50_000));
+    {
+        let compression = Compression {
+            codec: Codec::Zstd(0),
+            min_value_sz: 64,
+        };
+        let store = Store::open(dir.path(), options().compression(compression)).unwrap();
+        for i in 0..200 {
+            store.put(format!("key{i}"), value(i)).unwrap();
+        }
+    }
+
+    // without compression the values take far more files than they did, each
+    // merge gets as far as it can and the next one carries on
+    let store = open(&dir);
+    for _ in 0..3 {
+        store.merge_and_compact().unwrap();
+        for i in 0..200 {
+            assert_eq!(store.get(format!("key{i}")).unwrap(), Some(value(i)));
+        }
+    }
+    let leftovers = fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            return path.extension().is_some_and(|ext| ext == "merge");
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.dead_bytes(), 0);
+    drop(store);
+
+    let store = reopen(&dir, options());
+    assert_eq!(store.stats().unwrap().dead_bytes(), 0);
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{i}")).unwrap(), Some(value(i)));
+    }
+}
+
+#[test]
+fn one_writer_at_a_time() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+    store.put("a".to_string(), "1".to_string()).unwrap();
+
+    assert!(matches!(
+        Store::open(dir.path(), options()),
+        Err(Error::Locked { .. })
+    ));
+
+    let reader = Store::open(dir.path(), Options::default().read_only(true)).unwrap();
+    assert_eq!(reader.get("a".to_string()).unwrap().as_deref(), Some("1"));
+    assert!(matches!(
+        reader.put("b".to_string(), "2".to_string()),
+        Err(Error::ReadOnly)
+    ));
+
+    drop(store);
+    open(&dir);
+}
+
+#[test]
+fn missing_directory() {
+    let dir = TempDir::new();
+    assert!(matches!(
+        Store::open(dir.path(), Options::default()),
+        Err(Error::Io(_))
+    ));
+}
+
+fn file_ids(dir: &TempDir) -> Vec<u64> {
+    return data_files(dir)
+        .iter()
+        .map(|p| p.file_name().unwrap().to_str().unwrap().parse().unwrap())
+        .collect();
+}
+
+#[test]
+fn file_ids_are_a_sequence() {
+    let dir = TempDir::new();
+    let options = || options().max_file_size(256);
+    {
+        let store = Store::open(dir.path(), options()).unwrap();
+        for i in 0..40 {
+            store.put(format!("key{}", i % 5), "x".repeat(50)).unwrap();
+        }
+        // far more files than seconds went by
+        assert_eq!(
+            file_ids(&dir),
+            (1..=file_ids(&dir).len() as u64).collect::<Vec<_>>()
+        );
+
+        let before = *file_ids(&dir).last().unwrap();
+        store.merge_and_compact().unwrap();
+        let after = file_ids(&dir);
+        assert!(after.iter().all(|id| *id > before));
+        store.put("key0".to_string(), "after".to_string()).unwrap();
+    }
+
+    let newest = *file_ids(&dir).last().unwrap();
+    let store = Store::open(dir.path(), options()).unwrap();
+    assert_eq!(
+        store.get("key0".to_string()).unwrap().as_deref(),
+        Some("after")
+    );
+    for i in 0..20 {
+        store.put(format!("more{i}"), "x".repeat(50)).unwrap();
+    }
+    assert!(file_ids(&dir).iter().filter(|id| **id > newest).count() > 1);
+}
+
+#[test]
+fn stores_without_manifest_continue_after_newest_file() {
+    let dir = TempDir::new();
+    {
+        let store = open(&dir);
+        store.put("a".to_string(), "1".to_string()).unwrap();
+    }
+    // a store from before the manifest, with an id of seconds since epoch
+    fs::rename(data_files(&dir)[0].clone(), dir.path().join("1710000000")).unwrap();
+    fs::remove_file(dir.path().join("bitcask.manifest")).unwrap();
+
+    let store = Store::open(dir.path(), options().max_file_size(64)).unwrap();
+    assert_eq!(store.get("a".to_string()).unwrap().as_deref(), Some("1"));
+    store.put("b".to_string(), "x".repeat(100)).unwrap();
+    assert_eq!(file_ids(&dir), [1710000000, 1710000001]);
+}
+
+#[test]
+fn data_file_newer_than_manifest() {
+    let dir = TempDir::new();
+    drop(open(&dir));
+    fs::copy(&data_files(&dir)[0], dir.path().join("1000")).unwrap();
+
+    match Store::open(dir.path(), options()) {
+        Err(Error::Corruption { file_id, .. }) => assert_eq!(file_id, "1000"),
+        other => panic!("{other:?}"),
+    }
+    assert!(matches!(
+        Store::open(dir.path(), Options::default().read_only(true)),
+        Err(Error::Corruption { .. })
+    ));
+}
+
+#[test]
+fn max_file_size_must_fit_records() {
+    let dir = TempDir::new();
+    for max_file_sz in [0, 5] {
+        assert!(matches!(
+            Store::open(dir.path(), options().max_file_size(max_file_sz)),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    let store = Store::open(dir.path(), options().max_file_size(6)).unwrap();
+    store.put("a".to_string(), "1".to_string()).unwrap();
+    store.merge_and_compact().unwrap();
+    assert_eq!(store.get("a".to_string()).unwrap().as_deref(), Some("1"));
+}
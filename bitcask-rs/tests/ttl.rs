@@ -0,0 +1,153 @@
+//! Keys put with a time to live, as they expire on reads, loads and merges
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcask::{CompactionPolicy, Store};
+use common::{data_files, open, options, reopen, TempDir};
+
+const TTL: Duration = Duration::from_secs(1);
+
+/// Expiry has a granularity of seconds
+fn wait_for_expiry() {
+    thread::sleep(TTL + Duration::from_millis(1100));
+}
+
+fn get(store: &Store, key: &str) -> Option<String> {
+    return store.get(key.to_string()).unwrap();
+}
+
+#[test]
+fn expired_keys_read_as_missing() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+    store
+        .put_with_ttl("session".to_string(), "abc".to_string(), TTL)
+        .unwrap();
+    store.put("user".to_string(), "bob".to_string()).unwrap();
+    assert_eq!(get(&store, "session").as_deref(), Some("abc"));
+
+    wait_for_expiry();
+    assert_eq!(get(&store, "session"), None);
+    assert_eq!(get(&store, "user").as_deref(), Some("bob"));
+
+    // putting it again without a ttl keeps it
+    store.put("session".to_string(), "def".to_string()).unwrap();
+    assert_eq!(get(&store, "session").as_deref(), Some("def"));
+}
+
+#[test]
+fn expired_keys_are_garbage_once_loaded() {
+    let dir = TempDir::new();
+    {
+        let store = open(&dir);
+        store.put("session".to_string(), "old".to_string()).unwrap();
+        store
+            .put_with_ttl("session".to_string(), "abc".to_string(), TTL)
+            .unwrap();
+        store.put("user".to_string(), "bob".to_string()).unwrap();
+    }
+    wait_for_expiry();
+
+    let store = open(&dir);
+    assert_eq!(get(&store, "session"), None);
+    assert_eq!(store.keys().collect::<Vec<_>>(), [b"user".to_vec()]);
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.keys, 1);
+    assert!(stats.dead_bytes() > 0);
+}
+
+#[test]
+fn keys_expiring_while_open_become_garbage() {
+    let dir = TempDir::new();
+    // checks often, but never finds enough garbage to merge
+    let policy = CompactionPolicy {
+        garbage_ratio: 2.0,
+        max_disk_usage: None,
+        check_interval: Duration::from_millis(50),
+    };
+    let store = Store::open(dir.path(), options().compaction(policy)).unwrap();
+    store
+        .put_with_ttl("session".to_string(), "abc".to_string(), TTL)
+        .unwrap();
+    store.put("user".to_string(), "bob".to_string()).unwrap();
+    assert_eq!(store.stats().unwrap().dead_bytes(), 0);
+
+    wait_for_expiry();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.keys, 1);
+    assert!(stats.dead_bytes() > 0);
+    assert_eq!(get(&store, "session"), None);
+}
+
+#[test]
+fn full_merge_drops_expired_records() {
+    let dir = TempDir::new();
+    let store = open(&dir);
+    store.put("session".to_string(), "old".to_string()).unwrap();
+    store
+        .put_with_ttl("session".to_string(), "abc".to_string(), TTL)
+        .unwrap();
+    store
+        .put_with_ttl(
+            "later".to_string(),
+            "xyz".to_string(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    wait_for_expiry();
+
+    store.merge_and_compact().unwrap();
+    assert_eq!(get(&store, "session"), None);
+    assert_eq!(get(&store, "later").as_deref(), Some("xyz"));
+    drop(store);
+
+    let store = open(&dir);
+    assert_eq!(get(&store, "session"), None);
+    assert_eq!(get(&store, "later").as_deref(), Some("xyz"));
+    assert_eq!(store.stats().unwrap().dead_bytes(), 0);
+}
+
+#[test]
+fn partial_merge_leaves_a_tombstone_for_expired_records() {
+    let dir = TempDir::new();
+    let policy = CompactionPolicy {
+        garbage_ratio: 0.5,
+        max_disk_usage: None,
+        check_interval: Duration::from_millis(50),
+    };
+    let options = || options().max_file_size(1024);
+    let store = Store::open(dir.path(), options().compaction(policy)).unwrap();
+
+    // an older file the merge leaves alone still holds the key
+    store.put("session".to_string(), "old".to_string()).unwrap();
+    for i in 0..20 {
+        store.put(format!("user{i}"), "x".repeat(40)).unwrap();
+    }
+    let expiring_in = data_files(&dir).pop().unwrap();
+    store
+        .put_with_ttl("session".to_string(), "abc".to_string(), TTL)
+        .unwrap();
+    wait_for_expiry();
+
+    // overwrites fill the file of the expired record with garbage until it is
+    // rotated away from and merged
+    let start = Instant::now();
+    while expiring_in.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10), "never merged");
+        store.put("counter".to_string(), "x".repeat(40)).unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(get(&store, "session"), None);
+    drop(store);
+
+    let store = reopen(&dir, options());
+    assert_eq!(get(&store, "session"), None);
+    assert_eq!(get(&store, "user19").as_deref(), Some(&*"x".repeat(40)));
+}